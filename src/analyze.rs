@@ -0,0 +1,374 @@
+use crate::{
+    fs::{FileSearcher, git_changed_files},
+    ollama::{GenerateRequest, GenerateResponse, LLMClient, ModelParameters},
+    prompt::{AddedFile, PromptBuilder},
+};
+use anyhow::anyhow;
+use futures::stream::Stream;
+use std::path::{Path, PathBuf};
+
+/// The system prompt `acodeh run` sends unless given another.
+pub const DEFAULT_SYSTEM_PROMPT: &str = include_str!("system.in");
+
+/// Finds the files, documents and images of the context and adds them to a
+/// [`PromptBuilder`]; the pipeline behind both [`Analyzer`] and `acodeh run`.
+#[derive(Debug, Clone)]
+pub struct ContextLoader {
+    paths: Vec<PathBuf>,
+    files: Option<Vec<PathBuf>>,
+    staged: bool,
+    modified: bool,
+    includes: Vec<PathBuf>,
+    excludes: Vec<PathBuf>,
+    extensions: Option<String>,
+    exclude_extensions: Option<String>,
+    max_depth: usize,
+    overall: bool,
+    follow_links: bool,
+    include_hidden: bool,
+    tree: bool,
+    diff: bool,
+    images: Vec<PathBuf>,
+    context_files: Vec<PathBuf>,
+}
+
+impl Default for ContextLoader {
+    fn default() -> Self {
+        Self {
+            paths: vec![],
+            files: None,
+            staged: false,
+            modified: false,
+            includes: vec![],
+            excludes: vec![],
+            extensions: None,
+            exclude_extensions: None,
+            max_depth: usize::MAX,
+            overall: false,
+            follow_links: false,
+            include_hidden: true,
+            tree: false,
+            diff: false,
+            images: vec![],
+            context_files: vec![],
+        }
+    }
+}
+
+impl ContextLoader {
+    pub fn paths(mut self, paths: Vec<PathBuf>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    /// Adds exactly these files instead of scanning `paths`.
+    pub fn files(mut self, files: Option<Vec<PathBuf>>) -> Self {
+        self.files = files;
+        self
+    }
+
+    /// Only scans the files staged in git, limited to `paths` if any.
+    pub fn staged(mut self, flag: bool) -> Self {
+        self.staged = flag;
+        self
+    }
+
+    /// Only scans the files with uncommitted changes, limited to `paths` if any.
+    pub fn modified(mut self, flag: bool) -> Self {
+        self.modified = flag;
+        self
+    }
+
+    pub fn includes(mut self, includes: Vec<PathBuf>) -> Self {
+        self.includes = includes;
+        self
+    }
+
+    pub fn excludes(mut self, excludes: Vec<PathBuf>) -> Self {
+        self.excludes = excludes;
+        self
+    }
+
+    pub fn extensions(mut self, extensions: Option<String>) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    pub fn exclude_extensions(mut self, extensions: Option<String>) -> Self {
+        self.exclude_extensions = extensions;
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn overall(mut self, flag: bool) -> Self {
+        self.overall = flag;
+        self
+    }
+
+    pub fn follow_links(mut self, flag: bool) -> Self {
+        self.follow_links = flag;
+        self
+    }
+
+    pub fn include_hidden(mut self, flag: bool) -> Self {
+        self.include_hidden = flag;
+        self
+    }
+
+    /// Also adds the directory tree of each path.
+    pub fn tree(mut self, flag: bool) -> Self {
+        self.tree = flag;
+        self
+    }
+
+    /// Adds the `git diff` of the paths instead of the files themselves.
+    pub fn diff(mut self, flag: bool) -> Self {
+        self.diff = flag;
+        self
+    }
+
+    pub fn images(mut self, images: Vec<PathBuf>) -> Self {
+        self.images = images;
+        self
+    }
+
+    /// Files added whole as `<document>`s, outside the `<files>` section.
+    pub fn context_files(mut self, context_files: Vec<PathBuf>) -> Self {
+        self.context_files = context_files;
+        self
+    }
+
+    /// The files the context is read from, in order; errors are directories or
+    /// files the scan couldn't read.
+    pub fn scan(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<PathBuf>> + '_> {
+        if let Some(files) = &self.files {
+            return Ok(Box::new(files.iter().cloned().map(Ok)) as Box<dyn Iterator<Item = _>>);
+        }
+        let start_paths = if self.staged || self.modified {
+            git_changed_files(&self.paths, self.staged)?
+        } else {
+            self.paths.clone()
+        };
+        Ok(Box::new(start_paths.into_iter().flat_map(|start_path| {
+            FileSearcher::new(start_path)
+                .overall(self.overall)
+                .follow_links(self.follow_links)
+                .include_hidden(self.include_hidden)
+                .gitignore(true)
+                .skip_binary(true)
+                .max_depth(self.max_depth)
+                .includes(&self.includes)
+                .excludes(&self.excludes)
+                .extensions(self.extensions.as_ref())
+                .exclude_extensions(self.exclude_extensions.as_ref())
+                .into_iter()
+                .map(|result| result.map_err(anyhow::Error::from))
+                .filter(|result| result.as_ref().map_or(true, |path| path.is_file()))
+        })))
+    }
+
+    /// Adds everything to `prompt_builder`, calling `on_file` as each scanned file is
+    /// added or skipped; returns the errors that didn't stop loading, such as an
+    /// unreadable directory or a tree that didn't fit.
+    pub async fn load<F>(
+        &self,
+        prompt_builder: &mut PromptBuilder,
+        on_file: F,
+    ) -> anyhow::Result<Vec<anyhow::Error>>
+    where
+        F: FnMut(&PromptBuilder, &Path, &anyhow::Result<AddedFile>),
+    {
+        let mut errors = vec![];
+        if self.tree {
+            for start_path in &self.paths {
+                if let Err(error) =
+                    prompt_builder.add_tree(start_path, self.max_depth, &self.excludes)
+                {
+                    errors.push(error);
+                }
+            }
+        }
+        for image in &self.images {
+            prompt_builder.add_image(image).await?;
+        }
+        for context_file in &self.context_files {
+            let content = tokio::fs::read_to_string(context_file)
+                .await
+                .map_err(|error| {
+                    anyhow!("Could not read context file {context_file:?}: {error}")
+                })?;
+            prompt_builder.add_document(format!(
+                "<document path=\"{}\">\n{}\n</document>",
+                context_file.display(),
+                content.trim_end()
+            ))?;
+        }
+        if self.diff {
+            prompt_builder.add_diff(&self.paths)?;
+            return Ok(errors);
+        }
+
+        let paths = self
+            .scan()?
+            .filter_map(|result| result.map_err(|error| errors.push(error)).ok());
+        prompt_builder.add_files(paths, on_file).await?;
+        Ok(errors)
+    }
+}
+
+/// Scans paths, builds the prompt and streams the model's answer, the same way
+/// `acodeh run` does: with its system prompt unless replaced, and with `num_ctx`
+/// capped by the model's context window unless the [`PromptBuilder`] sets limits.
+/// Unlike `run`, it doesn't check that `format` answers are valid JSON.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use acodeh::{analyze::Analyzer, ollama::LLMClient};
+/// use futures::StreamExt;
+///
+/// let client = LLMClient::default();
+/// let mut stream = Analyzer::new(client, "qwen2.5-coder", "Explain this crate")
+///     .paths(vec!["src".into()])
+///     .stream()
+///     .await?;
+/// while let Some(response) = stream.next().await {
+///     print!("{}", response.response);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Analyzer {
+    client: LLMClient,
+    model: String,
+    prompt: String,
+    context_loader: ContextLoader,
+    system: Option<String>,
+    options: ModelParameters,
+    stop_sequences: Vec<String>,
+    format: Option<serde_json::Value>,
+    prompt_builder: PromptBuilder,
+}
+
+impl Analyzer {
+    pub fn new(client: LLMClient, model: &str, prompt: &str) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            prompt: prompt.to_string(),
+            context_loader: ContextLoader::default(),
+            system: Some(DEFAULT_SYSTEM_PROMPT.to_string()),
+            options: ModelParameters::default(),
+            stop_sequences: vec![],
+            format: None,
+            prompt_builder: PromptBuilder::new(String::new()),
+        }
+    }
+
+    pub fn paths(mut self, paths: Vec<PathBuf>) -> Self {
+        self.context_loader = self.context_loader.paths(paths);
+        self
+    }
+
+    pub fn includes(mut self, includes: Vec<PathBuf>) -> Self {
+        self.context_loader = self.context_loader.includes(includes);
+        self
+    }
+
+    pub fn excludes(mut self, excludes: Vec<PathBuf>) -> Self {
+        self.context_loader = self.context_loader.excludes(excludes);
+        self
+    }
+
+    pub fn extensions(mut self, extensions: Option<String>) -> Self {
+        self.context_loader = self.context_loader.extensions(extensions);
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.context_loader = self.context_loader.max_depth(max_depth);
+        self
+    }
+
+    /// Replaces the whole scan configuration, for the options without a shortcut here.
+    pub fn context_loader(mut self, context_loader: ContextLoader) -> Self {
+        self.context_loader = context_loader;
+        self
+    }
+
+    /// Replaces [`DEFAULT_SYSTEM_PROMPT`]; `None` sends no system prompt.
+    pub fn system(mut self, system: Option<String>) -> Self {
+        self.system = system;
+        self
+    }
+
+    pub fn options(mut self, options: ModelParameters) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences;
+        self
+    }
+
+    /// `"json"` or a JSON schema the answer has to follow.
+    pub fn format(mut self, format: Option<serde_json::Value>) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Starts from a preconfigured builder (context limits, comment stripping, ...)
+    /// instead of the defaults; its prompt is replaced by the analyzer's.
+    pub fn prompt_builder(mut self, prompt_builder: PromptBuilder) -> Self {
+        self.prompt_builder = prompt_builder;
+        self
+    }
+
+    /// Scans the paths and returns the loaded builder without contacting the model.
+    pub async fn load(self) -> anyhow::Result<PromptBuilder> {
+        let Self {
+            prompt,
+            context_loader,
+            mut prompt_builder,
+            ..
+        } = self;
+        prompt_builder.set_prompt(prompt);
+        context_loader
+            .load(&mut prompt_builder, |_, _, _| {})
+            .await?;
+        Ok(prompt_builder)
+    }
+
+    pub async fn stream(mut self) -> anyhow::Result<impl Stream<Item = GenerateResponse>> {
+        let client = self.client.clone();
+        let model = self.model.clone();
+        let system = self.system.clone();
+        let options = self.options.clone();
+        let stop_sequences = std::mem::take(&mut self.stop_sequences);
+        let format = self.format.take();
+        if !self.prompt_builder.has_context_limits()
+            && let Ok(Some(context_length)) = client.context_length(&model).await
+        {
+            self.prompt_builder = self
+                .prompt_builder
+                .max_context_ceiling(Some(context_length));
+        }
+        let prompt_builder = self.load().await?;
+        let (prompt, prompt_stats) = prompt_builder.build()?;
+
+        let mut request = GenerateRequest::new(&model, &client)
+            .options(options)
+            .stop_sequences(stop_sequences)
+            .format(format)
+            .images(prompt_builder.images().clone())
+            .num_ctx_options(prompt_stats.max_context);
+        if let Some(system) = &system {
+            request = request.system(system);
+        }
+        Ok(request.prompt_stream(&prompt).await?)
+    }
+}
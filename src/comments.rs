@@ -0,0 +1,159 @@
+struct Syntax {
+    line: &'static [&'static str],
+    block: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    char_literals: bool,
+    line_needs_boundary: bool,
+}
+
+const C_FAMILY: Syntax = Syntax {
+    line: &["//"],
+    block: Some(("/*", "*/")),
+    quotes: &['"', '`'],
+    char_literals: true,
+    line_needs_boundary: false,
+};
+
+const JAVASCRIPT: Syntax = Syntax {
+    line: &["//"],
+    block: Some(("/*", "*/")),
+    quotes: &['"', '\'', '`'],
+    char_literals: false,
+    line_needs_boundary: false,
+};
+
+const HASH: Syntax = Syntax {
+    line: &["#"],
+    block: None,
+    quotes: &['"', '\''],
+    char_literals: false,
+    line_needs_boundary: false,
+};
+
+const SHELL: Syntax = Syntax {
+    line: &["#"],
+    block: None,
+    quotes: &['"', '\''],
+    char_literals: false,
+    line_needs_boundary: true,
+};
+
+const SQL: Syntax = Syntax {
+    line: &["--"],
+    block: Some(("/*", "*/")),
+    quotes: &['"', '\''],
+    char_literals: false,
+    line_needs_boundary: false,
+};
+
+const LUA: Syntax = Syntax {
+    line: &["--"],
+    block: Some(("--[[", "]]")),
+    quotes: &['"', '\''],
+    char_literals: false,
+    line_needs_boundary: false,
+};
+
+fn syntax(extension: &str) -> Option<&'static Syntax> {
+    match extension {
+        "rs" | "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "hh" | "java" | "kt" | "kts"
+        | "scala" | "go" | "swift" | "cs" | "dart" | "proto" => Some(&C_FAMILY),
+        "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" => Some(&JAVASCRIPT),
+        "py" | "toml" | "yaml" | "yml" | "rb" | "pl" | "r" | "cmake" => Some(&HASH),
+        "sh" | "bash" | "zsh" | "fish" => Some(&SHELL),
+        "sql" => Some(&SQL),
+        "lua" => Some(&LUA),
+        _ => None,
+    }
+}
+
+fn trim_line_start(output: &mut String) -> bool {
+    let line_start = output.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    if output[line_start..].trim().is_empty() {
+        output.truncate(line_start);
+        true
+    } else {
+        output.truncate(output.trim_end_matches([' ', '\t']).len());
+        false
+    }
+}
+
+pub fn strip_comments(content: &str, extension: &str) -> String {
+    let Some(syntax) = syntax(&extension.to_lowercase()) else {
+        return content.to_string();
+    };
+
+    let mut output = String::with_capacity(content.len());
+    let mut index = 0;
+    let mut skip_newline = false;
+    while index < content.len() {
+        let rest = &content[index..];
+        let character = rest.chars().next().unwrap_or_default();
+
+        if skip_newline {
+            skip_newline = false;
+            if let Some(after_newline) = rest.strip_prefix('\n') {
+                index = content.len() - after_newline.len();
+                continue;
+            }
+        }
+
+        if let Some((start, end)) = syntax.block
+            && rest.starts_with(start)
+        {
+            let comment_end = rest[start.len()..]
+                .find(end)
+                .map(|position| start.len() + position + end.len())
+                .unwrap_or(rest.len());
+            index += comment_end;
+            let rest_of_line = content[index..].trim_start_matches([' ', '\t']);
+            if (rest_of_line.is_empty() || rest_of_line.starts_with('\n'))
+                && trim_line_start(&mut output)
+            {
+                index = content.len() - rest_of_line.len();
+                skip_newline = true;
+            }
+            continue;
+        }
+
+        let is_shebang = index == 0 && rest.starts_with("#!");
+        let at_boundary = !syntax.line_needs_boundary
+            || output.is_empty()
+            || output.ends_with(char::is_whitespace);
+        if !is_shebang && at_boundary && syntax.line.iter().any(|line| rest.starts_with(line)) {
+            index += rest.find('\n').unwrap_or(rest.len());
+            skip_newline = trim_line_start(&mut output);
+            continue;
+        }
+
+        let is_char_literal = syntax.char_literals && character == '\'' && {
+            let mut next = rest.chars().skip(1);
+            matches!(
+                (next.next(), next.next()),
+                (Some('\\'), _) | (Some(_), Some('\''))
+            )
+        };
+        if syntax.quotes.contains(&character) || is_char_literal {
+            let mut end = character.len_utf8();
+            let mut escaped = false;
+            for literal_character in rest[end..].chars() {
+                end += literal_character.len_utf8();
+                if escaped {
+                    escaped = false;
+                } else if literal_character == '\\' {
+                    escaped = true;
+                } else if literal_character == character {
+                    break;
+                }
+            }
+            output.push_str(&rest[..end]);
+            index += end;
+            continue;
+        }
+
+        output.push(character);
+        index += character.len_utf8();
+    }
+
+    output
+}
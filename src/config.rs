@@ -0,0 +1,56 @@
+use crate::ollama::ModelParameters;
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+const CONFIG_FILE_NAME: &str = "acodeh.toml";
+
+/// Defaults loaded from `acodeh.toml`, searched in the current directory and
+/// then in `$XDG_CONFIG_HOME/acodeh/`. Values set here are only used when the
+/// matching CLI flag (or its environment variable) is absent.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub model: Option<String>,
+    pub api_url: Option<String>,
+    pub max_context: Option<u64>,
+    pub max_context_ceiling: Option<u64>,
+    pub extensions: Option<String>,
+    pub options: ModelParameters,
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        match Self::search_paths().into_iter().find(|path| path.is_file()) {
+            Some(path) => Self::from_path(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// `max_context`, falling back to `options.num_ctx` so either key sets the default
+    /// for --max-context.
+    pub fn max_context(&self) -> Option<u64> {
+        self.max_context.or(self.options.num_ctx)
+    }
+
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|error| anyhow!("Invalid config file {path:?}: {error}"))
+    }
+
+    fn search_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(CONFIG_FILE_NAME)];
+
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        if let Some(config_home) = config_home {
+            paths.push(config_home.join("acodeh").join(CONFIG_FILE_NAME));
+        }
+
+        paths
+    }
+}
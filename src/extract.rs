@@ -0,0 +1,455 @@
+use anyhow::anyhow;
+use html2text::render::RichAnnotation;
+use quick_xml::{Reader, escape::resolve_predefined_entity, events::Event};
+use std::{fs::File, io::Read, path::Path};
+
+pub const EXTRACTED_EXTENSIONS: &[&str] = &["pdf", "docx", "epub", "xlsx", "xlsm", "xls", "ods"];
+pub const SPREADSHEET_EXTENSIONS: &[&str] = &["xlsx", "xlsm", "xls", "ods"];
+
+pub fn docx_text<P: AsRef<Path>>(path: P) -> anyhow::Result<String> {
+    let path = path.as_ref();
+    let mut archive = zip::ZipArchive::new(File::open(path)?)
+        .map_err(|error| anyhow!("Invalid docx file {}: {error}", path.display()))?;
+
+    let mut document = String::new();
+    archive
+        .by_name("word/document.xml")
+        .map_err(|error| anyhow!("Invalid docx file {}: {error}", path.display()))?
+        .read_to_string(&mut document)?;
+
+    let mut reader = Reader::from_str(&document);
+    let mut text = String::new();
+    let mut in_text = false;
+    let mut cell_depth = 0;
+    loop {
+        match reader.read_event()? {
+            Event::Start(tag) => match tag.local_name().as_ref() {
+                "t" => in_text = true,
+                "tc" => cell_depth += 1,
+                _ => {}
+            },
+            Event::End(tag) => match tag.local_name().as_ref() {
+                "t" => in_text = false,
+                "p" if cell_depth > 0 => text.push(' '),
+                "p" => text.push('\n'),
+                "tc" => {
+                    cell_depth -= 1;
+                    text.truncate(text.trim_end_matches(' ').len());
+                    text.push('\t');
+                }
+                "tr" => {
+                    text.truncate(text.trim_end_matches('\t').len());
+                    text.push('\n');
+                }
+                _ => {}
+            },
+            Event::Empty(tag) => match tag.local_name().as_ref() {
+                "tab" => text.push('\t'),
+                "br" | "cr" => text.push('\n'),
+                _ => {}
+            },
+            Event::Text(content) if in_text => text.push_str(&content.xml10_content()),
+            Event::GeneralRef(reference) if in_text => {
+                if let Some(character) = reference.resolve_char_ref()? {
+                    text.push(character);
+                } else if let Some(entity) = resolve_predefined_entity(&reference) {
+                    text.push_str(entity);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(text)
+}
+
+pub fn epub_text<P: AsRef<Path>>(path: P) -> anyhow::Result<String> {
+    let path = path.as_ref();
+    let mut document = epub::doc::EpubDoc::new(path)
+        .map_err(|error| anyhow!("Invalid epub file {}: {error}", path.display()))?;
+    let nav_id = document.get_nav_id();
+
+    let mut chapters = vec![];
+    for item in document.spine.clone() {
+        let is_nav = Some(&item.idref) == nav_id.as_ref()
+            || document.resources.get(&item.idref).is_some_and(|resource| {
+                resource
+                    .properties
+                    .as_deref()
+                    .is_some_and(|properties| properties.split_whitespace().any(|p| p == "nav"))
+            });
+        if is_nav {
+            continue;
+        }
+        let Some((content, mime)) = document.get_resource_str(&item.idref) else {
+            continue;
+        };
+        if !mime.contains("html") {
+            continue;
+        }
+
+        let lines = html2text::config::rich()
+            .lines_from_read(content.as_bytes(), usize::MAX)
+            .map_err(|error| anyhow!("Invalid epub chapter {}: {error}", item.idref))?;
+        let text = lines
+            .iter()
+            .map(|line| {
+                line.tagged_strings()
+                    .filter(|string| {
+                        !string
+                            .tag
+                            .iter()
+                            .any(|annotation| matches!(annotation, RichAnnotation::Image(_)))
+                    })
+                    .map(|string| &string.s[..])
+                    .collect::<String>()
+                    .trim_end()
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !text.trim().is_empty() {
+            chapters.push(text.trim().to_string());
+        }
+    }
+    Ok(chapters.join("\n\n"))
+}
+
+pub fn html_text(content: &str) -> anyhow::Result<String> {
+    let text = html2text::config::plain()
+        .string_from_read(content.as_bytes(), usize::MAX)
+        .map_err(|error| anyhow!("Invalid HTML: {error}"))?;
+    Ok(text
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+pub fn notebook_text(content: &str, include_markdown: bool) -> anyhow::Result<String> {
+    let notebook: serde_json::Value =
+        serde_json::from_str(content).map_err(|error| anyhow!("Invalid notebook: {error}"))?;
+    let cells = notebook["cells"]
+        .as_array()
+        .ok_or_else(|| anyhow!("Invalid notebook: no cells"))?;
+
+    let mut text = vec![];
+    for cell in cells {
+        let source = match &cell["source"] {
+            serde_json::Value::Array(lines) => lines
+                .iter()
+                .filter_map(serde_json::Value::as_str)
+                .collect::<String>(),
+            serde_json::Value::String(source) => source.clone(),
+            _ => continue,
+        };
+        if source.trim().is_empty() {
+            continue;
+        }
+        match cell["cell_type"].as_str() {
+            Some("code") => text.push(source.trim_end().to_string()),
+            Some("markdown") if include_markdown => text.push(
+                source
+                    .trim_end()
+                    .lines()
+                    .map(|line| format!("# {line}").trim_end().to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            _ => {}
+        }
+    }
+    Ok(text.join("\n\n"))
+}
+
+/// Every non-empty sheet as CSV under a `# <sheet name>` line, keeping the first
+/// row and `max_rows` more; formula cells hold the value last computed for them.
+pub fn spreadsheet_text<P: AsRef<Path>>(path: P, max_rows: usize) -> anyhow::Result<String> {
+    use calamine::Reader as _;
+
+    let path = path.as_ref();
+    let mut workbook = calamine::open_workbook_auto(path)
+        .map_err(|error| anyhow!("Invalid spreadsheet {}: {error}", path.display()))?;
+
+    let mut sheets = vec![];
+    for name in workbook.sheet_names() {
+        let range = workbook
+            .worksheet_range(&name)
+            .map_err(|error| anyhow!("Invalid sheet {name:?} in {}: {error}", path.display()))?;
+        if range.is_empty() {
+            continue;
+        }
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        for row in range.rows().take(max_rows.saturating_add(1)) {
+            writer.write_record(row.iter().map(|cell| cell.to_string()))?;
+        }
+        let mut sheet = format!("# {name}\n");
+        sheet.push_str(&String::from_utf8(writer.into_inner()?)?);
+        let more_rows = range.height().saturating_sub(max_rows.saturating_add(1));
+        if more_rows > 0 {
+            sheet.push_str(&format!("...{more_rows} more rows\n"));
+        }
+        sheets.push(sheet.trim_end().to_string());
+    }
+    Ok(sheets.join("\n\n"))
+}
+
+pub fn table_preview(content: &str, delimiter: u8, max_rows: usize) -> Option<String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(content.as_bytes());
+    let mut records = reader.records();
+    let header = records.next()?.ok()?;
+
+    let row = |record: &csv::StringRecord| {
+        let cells = record
+            .iter()
+            .map(|cell| cell.replace('|', "\\|").replace(['\r', '\n'], " "))
+            .collect::<Vec<_>>();
+        format!("| {} |\n", cells.join(" | "))
+    };
+    let mut table = row(&header);
+    table.push_str(&format!("|{}\n", " --- |".repeat(header.len().max(1))));
+    let mut more_rows = 0;
+    for (index, record) in records.enumerate() {
+        let record = record.ok()?;
+        if index < max_rows {
+            table.push_str(&row(&record));
+        } else {
+            more_rows += 1;
+        }
+    }
+    if more_rows > 0 {
+        table.push_str(&format!("...{more_rows} more rows\n"));
+    }
+    table.pop();
+    Some(table)
+}
+
+pub fn markdown_text(content: &str) -> String {
+    use pulldown_cmark::{Event as MarkdownEvent, Options, Parser, Tag, TagEnd};
+
+    let mut text = String::new();
+    let mut image_depth = 0;
+    for event in Parser::new_ext(
+        content,
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH,
+    ) {
+        match event {
+            MarkdownEvent::Start(Tag::Image { .. }) => image_depth += 1,
+            MarkdownEvent::End(TagEnd::Image) => image_depth -= 1,
+            _ if image_depth > 0 => {}
+            MarkdownEvent::Text(value) | MarkdownEvent::Code(value) => text.push_str(&value),
+            MarkdownEvent::SoftBreak | MarkdownEvent::HardBreak => text.push('\n'),
+            MarkdownEvent::Start(Tag::Item) => text.push_str("- "),
+            MarkdownEvent::End(TagEnd::TableCell) => text.push('\t'),
+            MarkdownEvent::End(TagEnd::Item | TagEnd::TableHead | TagEnd::TableRow) => {
+                text.truncate(text.trim_end_matches([' ', '\t']).len());
+                text.push('\n');
+            }
+            MarkdownEvent::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::CodeBlock
+                | TagEnd::BlockQuote(_)
+                | TagEnd::List(_)
+                | TagEnd::Table,
+            ) => {
+                text.truncate(text.trim_end().len());
+                text.push_str("\n\n");
+            }
+            _ => {}
+        }
+    }
+
+    text.trim_end().to_string()
+}
+
+const ELIDED_BODY: &str = "__acodeh_elided__";
+
+struct ElideBodies;
+
+impl syn::visit_mut::VisitMut for ElideBodies {
+    fn visit_item_fn_mut(&mut self, item: &mut syn::ItemFn) {
+        *item.block = elided_block();
+    }
+
+    fn visit_impl_item_fn_mut(&mut self, item: &mut syn::ImplItemFn) {
+        item.block = elided_block();
+    }
+
+    fn visit_trait_item_fn_mut(&mut self, item: &mut syn::TraitItemFn) {
+        if let Some(block) = &mut item.default {
+            *block = elided_block();
+        }
+    }
+}
+
+fn elided_block() -> syn::Block {
+    syn::parse_quote!({ __acodeh_elided__ })
+}
+
+/// Rust source reduced to its declarations and doc comments, with every function
+/// body replaced by `{ ... }`; `None` when the file doesn't parse.
+pub fn rust_definitions(content: &str) -> Option<String> {
+    let mut file = syn::parse_file(content).ok()?;
+    syn::visit_mut::VisitMut::visit_file_mut(&mut ElideBodies, &mut file);
+
+    let mut definitions: Vec<String> = vec![];
+    let unparsed = prettyplease::unparse(&file);
+    let mut lines = unparsed.lines().peekable();
+    while let Some(line) = lines.next() {
+        if line.trim() == ELIDED_BODY
+            && let Some(last) = definitions.last_mut()
+            && last.ends_with('{')
+        {
+            last.push_str(" ... }");
+            lines.next_if(|line| line.trim() == "}");
+        } else {
+            definitions.push(line.to_string());
+        }
+    }
+    Some(definitions.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_text_drops_link_urls_and_images() {
+        let content = "# Guide\n\nSee [the docs](https://example.com/docs) and `cargo run`.\n\n\
+                       ![architecture diagram](images/arch.png)\n\n- one\n- two\n";
+        assert_eq!(
+            markdown_text(content),
+            "Guide\n\nSee the docs and cargo run.\n\n- one\n- two"
+        );
+    }
+
+    #[test]
+    fn html_text_drops_scripts_and_styles() {
+        let content = r#"<html>
+<head>
+  <title>Page</title>
+  <style>body { color: red; }</style>
+  <script>var tracking = "secret";</script>
+</head>
+<body>
+  <h1>Welcome</h1>
+  <p>Readable <b>text</b>.</p>
+  <script type="module">console.log("inline");</script>
+</body>
+</html>"#;
+        let text = html_text(content).unwrap();
+        assert_eq!(text, "# Welcome\n\nReadable **text**.");
+    }
+
+    #[test]
+    fn notebook_text_keeps_code_cells_and_optionally_markdown() {
+        let notebook = serde_json::json!({
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Load\n", "Reads the data."]},
+                {
+                    "cell_type": "code",
+                    "source": ["import pandas as pd\n", "df = pd.read_csv('data.csv')"],
+                    "outputs": [{"data": {"image/png": "iVBORw0KGgo="}}]
+                },
+                {"cell_type": "raw", "source": "raw text"},
+                {"cell_type": "code", "source": "df.describe()\n", "outputs": []},
+                {"cell_type": "code", "source": [], "outputs": []}
+            ],
+            "metadata": {"kernelspec": {"name": "python3"}}
+        })
+        .to_string();
+
+        assert_eq!(
+            notebook_text(&notebook, false).unwrap(),
+            "import pandas as pd\ndf = pd.read_csv('data.csv')\n\ndf.describe()"
+        );
+        assert_eq!(
+            notebook_text(&notebook, true).unwrap(),
+            "# # Load\n# Reads the data.\n\n\
+             import pandas as pd\ndf = pd.read_csv('data.csv')\n\ndf.describe()"
+        );
+    }
+
+    fn chapter(title: &str, body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>{title}</title></head>
+<body><h1>{title}</h1><p>{body}</p><img src="cover.png" alt="Cover art"/></body></html>"#
+        )
+    }
+
+    /// An EPUB whose manifest lists the chapters in the reverse of their spine order.
+    fn tiny_epub(path: &Path) {
+        use std::io::Write;
+        use zip::write::SimpleFileOptions;
+
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let files = [
+            ("mimetype", "application/epub+zip".to_string()),
+            (
+                "META-INF/container.xml",
+                r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>"#
+                    .to_string(),
+            ),
+            (
+                "OEBPS/content.opf",
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="id">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="id">tiny</dc:identifier>
+    <dc:title>Tiny</dc:title>
+    <dc:language>en</dc:language>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="second" href="second.xhtml" media-type="application/xhtml+xml"/>
+    <item id="first" href="first.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="nav"/>
+    <itemref idref="first"/>
+    <itemref idref="second"/>
+  </spine>
+</package>"#
+                    .to_string(),
+            ),
+            (
+                "OEBPS/nav.xhtml",
+                chapter("Contents", "First chapter, Second chapter"),
+            ),
+            ("OEBPS/first.xhtml", chapter("First chapter", "It begins.")),
+            ("OEBPS/second.xhtml", chapter("Second chapter", "It ends.")),
+        ];
+        for (name, content) in files {
+            zip.start_file(name, stored).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn epub_text_follows_the_spine_and_skips_nav_and_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiny.epub");
+        tiny_epub(&path);
+
+        assert_eq!(
+            epub_text(&path).unwrap(),
+            "# First chapter\n\nIt begins.\n\n# Second chapter\n\nIt ends."
+        );
+    }
+}
@@ -1,23 +1,200 @@
+use crate::extract::EXTRACTED_EXTENSIONS;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::{Walk, WalkBuilder};
 use std::{
     collections::VecDeque,
-    fs::ReadDir,
-    io::Result,
+    fs::File,
+    io::{Error, Read, Result},
     path::{Path, PathBuf},
+    process::Command,
 };
 
-#[derive(Debug)]
-enum InnerEntryPath {
-    Path(PathBuf),
-    DeferredPath(PathBuf),
+const BINARY_SNIFF_LEN: usize = 8 * 1024;
+
+fn git_output(args: &[&str], paths: &[PathBuf]) -> Result<String> {
+    let mut command = Command::new("git");
+    command.args(args);
+    if !paths.is_empty() {
+        command.arg("--").args(paths);
+    }
+    let output = command.output()?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .next()
+                .unwrap_or_default()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Lists the existing files under `paths` that are staged (`staged`) or that have
+/// any change in the working tree, untracked files included; deleted files are
+/// left out.
+pub fn git_changed_files(paths: &[PathBuf], staged: bool) -> Result<Vec<PathBuf>> {
+    let toplevel = PathBuf::from(git_output(&["rev-parse", "--show-toplevel"], &[])?.trim_end());
+    let changed_files = if staged {
+        git_output(&["diff", "--cached", "--name-only", "-z"], paths)?
+            .split('\0')
+            .filter(|path| !path.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    } else {
+        let status = git_output(
+            &["status", "--porcelain", "-z", "--untracked-files=all"],
+            paths,
+        )?;
+        let mut entries = status.split('\0').filter(|entry| !entry.is_empty());
+        let mut changed_files = vec![];
+        while let Some(entry) = entries.next() {
+            let (code, path) = entry.split_at(entry.len().min(3));
+            if code.contains(['R', 'C']) {
+                entries.next();
+            }
+            changed_files.push(path.to_string());
+        }
+        changed_files
+    };
+
+    let current_dir = std::env::current_dir()?;
+    Ok(changed_files
+        .into_iter()
+        .map(|path| toplevel.join(path))
+        .filter(|path| path.is_file())
+        .map(|path| {
+            path.strip_prefix(&current_dir)
+                .map(Path::to_path_buf)
+                .unwrap_or(path)
+        })
+        .collect())
+}
+
+pub fn looks_binary(sample: &[u8]) -> bool {
+    if sample.starts_with(&[0xFF, 0xFE]) || sample.starts_with(&[0xFE, 0xFF]) {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+
+    let control_bytes = sample
+        .iter()
+        .filter(|byte| {
+            byte.is_ascii_control() && !matches!(byte, b'\t' | b'\n' | b'\r' | 0x0C | 0x1B)
+        })
+        .count();
+    control_bytes * 10 > sample.len() * 3
+}
+
+pub fn image_mime_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn is_binary_file(path: &Path) -> Result<bool> {
+    let mut sample = Vec::with_capacity(BINARY_SNIFF_LEN);
+    File::open(path)?
+        .take(BINARY_SNIFF_LEN as u64)
+        .read_to_end(&mut sample)?;
+    Ok(looks_binary(&sample))
+}
+
+#[derive(Debug, Clone)]
+enum PathPattern {
+    Literal(String),
+    Glob(GlobMatcher),
+}
+
+impl PathPattern {
+    fn new(pattern: &str) -> Self {
+        if pattern.contains(['*', '?', '[', '{'])
+            && let Ok(glob) = GlobBuilder::new(pattern).literal_separator(true).build()
+        {
+            PathPattern::Glob(glob.compile_matcher())
+        } else {
+            PathPattern::Literal(pattern.to_string())
+        }
+    }
+
+    fn is_match(&self, path: &Path, root: &Path) -> bool {
+        match self {
+            PathPattern::Literal(literal) => path.to_string_lossy().contains(&literal[..]),
+            PathPattern::Glob(glob) => {
+                glob.is_match(path)
+                    || path
+                        .strip_prefix(root)
+                        .is_ok_and(|relative_path| glob.is_match(relative_path))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct PathPatterns {
+    patterns: Vec<PathPattern>,
+    negated_patterns: Vec<PathPattern>,
+}
+
+impl PathPatterns {
+    fn new<P: AsRef<Path>>(items: &[P]) -> Self {
+        let mut path_patterns = PathPatterns::default();
+        for item in items {
+            let item = item.as_ref().to_string_lossy();
+            match item.strip_prefix('!') {
+                Some(negated) => path_patterns
+                    .negated_patterns
+                    .push(PathPattern::new(negated)),
+                None => path_patterns.patterns.push(PathPattern::new(&item)),
+            }
+        }
+        path_patterns
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.negated_patterns.is_empty()
+    }
+
+    fn is_match(&self, path: &Path, root: &Path, match_without_patterns: bool) -> bool {
+        let matched = if self.patterns.is_empty() {
+            match_without_patterns
+        } else {
+            self.patterns
+                .iter()
+                .any(|pattern| pattern.is_match(path, root))
+        };
+        matched
+            && !self
+                .negated_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(path, root))
+    }
 }
 
 #[derive(Default, Debug)]
 struct FileSearcherOptions {
     overall: bool,
+    gitignore: bool,
+    follow_links: bool,
+    skip_binary: bool,
+    skip_hidden: bool,
+    sorted: bool,
     max_depth: usize,
-    includes: Vec<String>,
-    excludes: Vec<String>,
+    includes: PathPatterns,
+    excludes: PathPatterns,
     extensions: Vec<String>,
+    excluded_extensions: Vec<String>,
 }
 
 #[derive(Debug, Default)]
@@ -34,6 +211,7 @@ impl FileSearcher {
                 start_path,
                 options: FileSearcherOptions {
                     max_depth: usize::MAX,
+                    sorted: true,
                     ..FileSearcherOptions::default()
                 },
             }
@@ -41,6 +219,7 @@ impl FileSearcher {
             FileSearcher {
                 options: FileSearcherOptions {
                     max_depth: usize::MAX,
+                    sorted: true,
                     ..FileSearcherOptions::default()
                 },
                 ..FileSearcher::default()
@@ -53,37 +232,76 @@ impl FileSearcher {
         self
     }
 
+    /// Skips paths matched by the `.gitignore`, `.ignore` and `.acodehignore` files
+    /// found while walking. Later files win on conflicting patterns, so `.acodehignore`
+    /// overrides `.ignore`, which overrides `.gitignore`, and a deeper directory's
+    /// files override its parents'.
+    pub fn gitignore(mut self, flag: bool) -> Self {
+        self.options.gitignore = flag;
+        self
+    }
+
+    /// Symlinks are skipped unless enabled; when following, a link pointing back
+    /// to one of its ancestors is reported as an error instead of being walked.
+    pub fn follow_links(mut self, flag: bool) -> Self {
+        self.options.follow_links = flag;
+        self
+    }
+
+    pub fn skip_binary(mut self, flag: bool) -> Self {
+        self.options.skip_binary = flag;
+        self
+    }
+
+    /// On by default; when disabled, dotfiles and dot-directories below the start
+    /// path are skipped, along with everything inside them.
+    pub fn include_hidden(mut self, flag: bool) -> Self {
+        self.options.skip_hidden = !flag;
+        self
+    }
+
+    /// On by default: entries are visited in byte order of their names, so the output
+    /// doesn't depend on the platform's directory listing order. Disabling it yields
+    /// them in listing order, which is a little faster on huge trees.
+    pub fn sorted(mut self, flag: bool) -> Self {
+        self.options.sorted = flag;
+        self
+    }
+
+    /// Depth is counted from the start path: 0 yields only the start path itself,
+    /// 1 adds its direct children, 2 their children, and so on.
     pub fn max_depth(mut self, max_depth: usize) -> Self {
         self.options.max_depth = max_depth;
         self
     }
 
+    /// Entries containing `*`, `?`, `[` or `{` are glob patterns matched against the
+    /// path, either whole or relative to the start path, with `*` stopping at `/` and
+    /// `**` spanning directories (`**/*.rs`); other entries match any path containing
+    /// them. An entry
+    /// prefixed with `!` rejects the paths it matches. A path matched by both
+    /// `includes` and `excludes` is excluded.
     pub fn includes<P: AsRef<Path>>(mut self, includes: &[P]) -> Self {
-        self.options.includes = includes
-            .iter()
-            .map(|item| item.as_ref().to_path_buf().to_string_lossy().to_string())
-            .collect::<Vec<_>>();
+        self.options.includes = PathPatterns::new(includes);
         self
     }
 
+    /// Same pattern syntax as `includes`; a `!` entry keeps paths that the other
+    /// exclude entries would drop.
     pub fn excludes<P: AsRef<Path>>(mut self, excludes: &[P]) -> Self {
-        self.options.excludes = excludes
-            .iter()
-            .map(|item| item.as_ref().to_path_buf().to_string_lossy().to_string())
-            .collect::<Vec<_>>();
+        self.options.excludes = PathPatterns::new(excludes);
         self
     }
 
     pub fn extensions(mut self, extensions: Option<impl AsRef<str>>) -> Self {
-        self.options.extensions = extensions
-            .map(|value| {
-                value
-                    .as_ref()
-                    .split(&[',', ';', '|', ' '][..])
-                    .map(|item| item.to_string())
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
+        self.options.extensions = parse_extensions(extensions);
+        self
+    }
+
+    /// Drops files with these extensions, multi-part ones like `min.js` included;
+    /// wins over `extensions` for files matching both.
+    pub fn exclude_extensions(mut self, extensions: Option<impl AsRef<str>>) -> Self {
+        self.options.excluded_extensions = parse_extensions(extensions);
         self
     }
 }
@@ -94,69 +312,107 @@ impl IntoIterator for FileSearcher {
     type IntoIter = IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
+        let mut walk_builder = WalkBuilder::new(&self.start_path);
+        walk_builder
+            .standard_filters(false)
+            .follow_links(self.options.follow_links)
+            .hidden(self.options.skip_hidden)
+            .max_depth(Some(self.options.max_depth));
+        if self.options.sorted {
+            walk_builder.sort_by_file_name(|a, b| a.cmp(b));
+        }
+        if self.options.gitignore {
+            walk_builder
+                .git_ignore(true)
+                .ignore(true)
+                .require_git(false)
+                .add_custom_ignore_filename(".acodehignore");
+        }
+
+        let gitignore = self.options.gitignore;
+        let follow_links = self.options.follow_links;
+        let root = self.start_path.clone();
+        let excludes = (!self.options.excludes.is_empty()
+            && self.options.excludes.negated_patterns.is_empty())
+        .then(|| self.options.excludes.clone());
+        walk_builder.filter_entry(move |entry| {
+            if gitignore && entry.file_name() == ".git" {
+                return false;
+            }
+            if !follow_links && entry.depth() > 0 && entry.path_is_symlink() {
+                return false;
+            }
+            let is_dir = entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_dir());
+            !(is_dir
+                && excludes
+                    .as_ref()
+                    .is_some_and(|excludes| excludes.is_match(entry.path(), &root, false)))
+        });
+
         IntoIter {
             options: self.options,
-            offset_depth: self.start_path.components().count(),
-            pending_paths: VecDeque::from([InnerEntryPath::Path(self.start_path)]),
-            current_read_directory: None,
+            root: self.start_path,
+            walk: walk_builder.build(),
+            deferred_directories: Vec::new(),
+            ready_paths: VecDeque::new(),
+            current_path: None,
+            skipped_directory: None,
         }
     }
 }
 
-#[derive(Debug)]
 pub struct IntoIter {
     options: FileSearcherOptions,
-    pending_paths: VecDeque<InnerEntryPath>,
-    current_read_directory: Option<ReadDir>,
-    offset_depth: usize,
+    root: PathBuf,
+    walk: Walk,
+    deferred_directories: Vec<(usize, PathBuf)>,
+    ready_paths: VecDeque<PathBuf>,
+    current_path: Option<PathBuf>,
+    skipped_directory: Option<PathBuf>,
 }
 
 impl IntoIter {
     fn inner_next(&mut self) -> Option<Result<PathBuf>> {
-        while !self.pending_paths.is_empty() || self.current_read_directory.is_some() {
-            if let Some(read_dir) = &mut self.current_read_directory {
-                for entry_result in read_dir {
-                    match entry_result {
-                        Ok(entry) => {
-                            let path = entry.path();
-                            let current_depth = path.components().count() - self.offset_depth;
-                            if (path.is_file() || path.is_dir())
-                                && current_depth <= self.options.max_depth
-                            {
-                                self.pending_paths.push_front(InnerEntryPath::Path(path));
-                            }
-                        }
-                        Err(error) => return Some(Err(error)),
-                    }
-                }
-                self.current_read_directory = None;
-            } else if let Some(entry_path) = self.pending_paths.pop_front() {
-                match entry_path {
-                    InnerEntryPath::DeferredPath(pending_path) => return Some(Ok(pending_path)),
-                    InnerEntryPath::Path(pending_path) => {
-                        if pending_path.is_dir() {
-                            match pending_path.read_dir() {
-                                Ok(read_dir) => {
-                                    self.current_read_directory = Some(read_dir);
-                                }
-                                Err(error) => return Some(Err(error)),
-                            }
-                            if self.options.overall {
-                                self.pending_paths
-                                    .push_front(InnerEntryPath::DeferredPath(pending_path));
-                            } else {
-                                return Some(Ok(pending_path));
-                            }
-                        } else {
-                            return Some(Ok(pending_path));
-                        }
-                    }
+        loop {
+            if let Some(path) = self.ready_paths.pop_front() {
+                self.current_path = Some(path.clone());
+                return Some(Ok(path));
+            }
+
+            let Some(result) = self.walk.next() else {
+                let (_, path) = self.deferred_directories.pop()?;
+                self.current_path = Some(path.clone());
+                return Some(Ok(path));
+            };
+            let entry = match result {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(Error::other(error))),
+            };
+            let depth = entry.depth();
+            let path = entry.into_path();
+
+            if let Some(skipped_directory) = &self.skipped_directory {
+                if path.starts_with(skipped_directory) {
+                    continue;
                 }
-            } else {
-                return None;
+                self.skipped_directory = None;
+            }
+
+            while let Some((directory_depth, _)) = self.deferred_directories.last()
+                && *directory_depth >= depth
+            {
+                let (_, directory) = self.deferred_directories.pop().unwrap();
+                self.ready_paths.push_back(directory);
+            }
+
+            if path.is_dir() && self.options.overall {
+                self.deferred_directories.push((depth, path));
+            } else if path.is_file() || path.is_dir() {
+                self.ready_paths.push_back(path);
             }
         }
-        None
     }
 }
 
@@ -173,43 +429,43 @@ impl Iterator for IntoIter {
             let to_excludes = if self.options.excludes.is_empty() {
                 false
             } else {
-                self.options
-                    .excludes
-                    .iter()
-                    .any(|item| path.to_string_lossy().contains(&item[..]))
+                self.options.excludes.is_match(&path, &self.root, false)
             };
             if to_excludes {
-                if path.is_dir() {
-                    self.skip_current_directory();
-                }
                 continue;
             }
 
             let to_includes = if self.options.includes.is_empty() {
                 true
             } else {
-                self.options
-                    .includes
-                    .iter()
-                    .any(|item| path.to_string_lossy().contains(&item[..]))
+                self.options.includes.is_match(&path, &self.root, true)
             };
             if !to_includes {
                 continue;
             }
 
-            let to_includes_extensions = if self.options.extensions.is_empty() {
-                true
-            } else if let Some(file_extension) = path.extension() {
-                self.options
-                    .extensions
-                    .iter()
-                    .any(|item| &file_extension.to_string_lossy() == item)
-            } else {
-                false
-            };
+            let to_includes_extensions = self.options.extensions.is_empty()
+                || has_extension(&path, &self.options.extensions);
             if !to_includes_extensions {
                 continue;
             }
+            if !self.options.excluded_extensions.is_empty()
+                && !path.is_dir()
+                && has_extension(&path, &self.options.excluded_extensions)
+            {
+                continue;
+            }
+
+            let has_extractor = path.extension().is_some_and(|extension| {
+                EXTRACTED_EXTENSIONS.contains(&&extension.to_string_lossy().to_lowercase()[..])
+            });
+            if self.options.skip_binary && !has_extractor && path.is_file() {
+                match is_binary_file(&path) {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(error) => return Some(Err(error)),
+                }
+            }
 
             return Some(Ok(path));
         }
@@ -217,6 +473,31 @@ impl Iterator for IntoIter {
     }
 }
 
+fn parse_extensions(extensions: Option<impl AsRef<str>>) -> Vec<String> {
+    extensions
+        .map(|value| {
+            value
+                .as_ref()
+                .split(&[',', ';', '|', ' '][..])
+                .map(|item| item.trim().trim_start_matches('.').to_lowercase())
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    let Some(file_name) = path.file_name() else {
+        return false;
+    };
+    let file_name = file_name.to_string_lossy().to_lowercase();
+    extensions.iter().any(|item| {
+        file_name
+            .strip_suffix(&item[..])
+            .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+    })
+}
+
 impl IntoIter {
     pub fn filter_path<P: FnMut(&PathBuf) -> bool>(self, predicate: P) -> FilterPath<Self, P> {
         FilterPath {
@@ -226,7 +507,7 @@ impl IntoIter {
     }
 
     pub fn skip_current_directory(&mut self) {
-        self.current_read_directory = None;
+        self.skipped_directory = self.current_path.take().filter(|path| path.is_dir());
     }
 }
 
@@ -274,6 +555,295 @@ where
     }
 
     pub fn skip_current_directory(&mut self) {
-        self.inner.current_read_directory = None;
+        self.inner.skip_current_directory();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory holding `files`, each containing its own path.
+    fn tree(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, file).unwrap();
+        }
+        dir
+    }
+
+    /// The files `searcher` yields, relative to `root` and `/`-separated.
+    fn scanned_files(searcher: FileSearcher, root: &Path) -> Vec<String> {
+        searcher
+            .into_iter()
+            .map(Result::unwrap)
+            .filter(|path| path.is_file())
+            .map(|path| {
+                path.strip_prefix(root)
+                    .unwrap()
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn max_depth_counts_from_the_start_path() {
+        let dir = tree(&["top.rs", "src/main.rs", "src/nested/deep.rs"]);
+        let root = dir.path();
+
+        assert!(scanned_files(FileSearcher::new(root).max_depth(0), root).is_empty());
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).max_depth(1), root),
+            ["top.rs"]
+        );
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).max_depth(2), root),
+            ["src/main.rs", "top.rs"]
+        );
+        assert_eq!(
+            scanned_files(FileSearcher::new(root), root),
+            ["src/main.rs", "src/nested/deep.rs", "top.rs"]
+        );
+    }
+
+    #[test]
+    fn has_extension_handles_case_compound_and_missing_extensions() {
+        let extensions = parse_extensions(Some(".RS, tar.gz,md"));
+        assert_eq!(extensions, ["rs", "tar.gz", "md"]);
+
+        assert!(has_extension(Path::new("src/MAIN.RS"), &extensions));
+        assert!(has_extension(Path::new("notes.Md"), &extensions));
+        assert!(has_extension(Path::new("backup.tar.gz"), &extensions));
+        assert!(has_extension(Path::new("BACKUP.TAR.GZ"), &extensions));
+        assert!(!has_extension(Path::new("backup.gz"), &extensions));
+
+        assert!(!has_extension(Path::new("Makefile"), &extensions));
+        assert!(!has_extension(Path::new("rs"), &extensions));
+        assert!(!has_extension(Path::new(".rs"), &extensions));
+        assert!(!has_extension(Path::new("tar.gz"), &extensions));
+    }
+
+    #[test]
+    fn includes_and_excludes_match_nested_globs() {
+        let dir = tree(&[
+            "README.md",
+            "src/lib.rs",
+            "src/deep/nested/mod.rs",
+            "src/deep/nested/mod_test.rs",
+            "target/debug/build.rs",
+        ]);
+        let root = dir.path();
+
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).includes(&["**/*.rs"]), root),
+            [
+                "src/deep/nested/mod.rs",
+                "src/deep/nested/mod_test.rs",
+                "src/lib.rs",
+                "target/debug/build.rs",
+            ]
+        );
+        // `*` stops at `/`, so only the direct children of src match.
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).includes(&["src/*.rs"]), root),
+            ["src/lib.rs"]
+        );
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).excludes(&["**/target/**"]), root),
+            [
+                "README.md",
+                "src/deep/nested/mod.rs",
+                "src/deep/nested/mod_test.rs",
+                "src/lib.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn negated_patterns_and_exclude_precedence() {
+        let dir = tree(&[
+            "src/lib.rs",
+            "src/deep/nested/mod.rs",
+            "src/deep/nested/mod_test.rs",
+        ]);
+        let root = dir.path();
+
+        assert_eq!(
+            scanned_files(
+                FileSearcher::new(root).includes(&["**/*.rs", "!**/*_test.rs"]),
+                root
+            ),
+            ["src/deep/nested/mod.rs", "src/lib.rs"]
+        );
+        // A `!` exclude keeps what the other excludes would drop.
+        assert_eq!(
+            scanned_files(
+                FileSearcher::new(root).excludes(&["src/deep/**", "!**/mod.rs"]),
+                root
+            ),
+            ["src/deep/nested/mod.rs", "src/lib.rs"]
+        );
+        // Matched by both, a path is excluded.
+        assert_eq!(
+            scanned_files(
+                FileSearcher::new(root)
+                    .includes(&["**/*.rs"])
+                    .excludes(&["**/nested/**"]),
+                root
+            ),
+            ["src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn acodehignore_excludes_a_git_tracked_file() {
+        let dir = tree(&["src/lib.rs", "fixtures/huge.json"]);
+        let root = dir.path();
+        for args in [&["init", "-q"][..], &["add", "."]] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(root)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        std::fs::write(root.join(".acodehignore"), "fixtures/\n").unwrap();
+
+        let tracked = Command::new("git")
+            .arg("ls-files")
+            .current_dir(root)
+            .output()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&tracked.stdout).contains("fixtures/huge.json"));
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).gitignore(true), root),
+            [".acodehignore", "src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn nested_gitignores_apply_to_their_own_directories() {
+        let dir = tree(&[
+            "debug.log",
+            "main.rs",
+            "app/generated/schema.rs",
+            "app/lib.rs",
+            "app/logs/important.log",
+            "app/logs/trace.log",
+            "other/generated/kept.rs",
+        ]);
+        let root = dir.path();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join("app/.gitignore"), "generated/\n").unwrap();
+        std::fs::write(root.join("app/logs/.gitignore"), "!important.log\n").unwrap();
+
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).gitignore(true), root),
+            [
+                ".gitignore",
+                "app/.gitignore",
+                "app/lib.rs",
+                "app/logs/.gitignore",
+                "app/logs/important.log",
+                "main.rs",
+                "other/generated/kept.rs",
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn self_referential_symlink_is_scanned_once() {
+        let dir = tree(&["a.rs", "nested/b.rs"]);
+        let root = dir.path();
+        std::os::unix::fs::symlink(root, root.join("nested/loop")).unwrap();
+
+        assert_eq!(
+            scanned_files(FileSearcher::new(root), root),
+            ["a.rs", "nested/b.rs"]
+        );
+
+        let mut files = vec![];
+        let mut errors = 0;
+        for result in FileSearcher::new(root).follow_links(true) {
+            match result {
+                Ok(path) if path.is_file() => files.push(path),
+                Ok(_) => {}
+                Err(_) => errors += 1,
+            }
+        }
+        assert_eq!(files, [root.join("a.rs"), root.join("nested/b.rs")]);
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn scan_order_does_not_depend_on_creation_order() {
+        let files = ["b.rs", "a/z.rs", "C.md", "a/b/c.rs", "_x.txt", "a.rs"];
+        let reversed: Vec<_> = files.iter().rev().copied().collect();
+        let dir = tree(&files);
+        let reversed_dir = tree(&reversed);
+
+        let expected = ["C.md", "_x.txt", "a/b/c.rs", "a/z.rs", "a.rs", "b.rs"];
+        assert_eq!(
+            scanned_files(FileSearcher::new(dir.path()), dir.path()),
+            expected
+        );
+        assert_eq!(
+            scanned_files(FileSearcher::new(reversed_dir.path()), reversed_dir.path()),
+            expected
+        );
+    }
+
+    #[test]
+    fn include_hidden_controls_dotfiles_and_dot_directories() {
+        let dir = tree(&[
+            ".env",
+            ".config/settings.toml",
+            "src/.local.rs",
+            "src/main.rs",
+        ]);
+        let root = dir.path();
+
+        assert_eq!(
+            scanned_files(FileSearcher::new(root), root),
+            [
+                ".config/settings.toml",
+                ".env",
+                "src/.local.rs",
+                "src/main.rs"
+            ]
+        );
+        assert_eq!(
+            scanned_files(FileSearcher::new(root).include_hidden(false), root),
+            ["src/main.rs"]
+        );
+    }
+
+    #[test]
+    fn exclude_extensions_win_over_extensions() {
+        let dir = tree(&["app.js", "app.min.js", "Cargo.lock", "lib.rs"]);
+        let root = dir.path();
+
+        assert_eq!(
+            scanned_files(
+                FileSearcher::new(root)
+                    .extensions(Some("js,lock"))
+                    .exclude_extensions(Some("min.js,lock")),
+                root
+            ),
+            ["app.js"]
+        );
+        assert_eq!(
+            scanned_files(
+                FileSearcher::new(root).exclude_extensions(Some(".min.js")),
+                root
+            ),
+            ["Cargo.lock", "app.js", "lib.rs"]
+        );
     }
 }
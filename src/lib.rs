@@ -1,3 +1,8 @@
+pub mod analyze;
+pub mod comments;
+pub mod config;
+pub mod extract;
 pub mod fs;
 pub mod ollama;
 pub mod prompt;
+pub mod token;
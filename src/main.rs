@@ -1,190 +1,1768 @@
-use acodeh::ollama::GenerateRequest;
-use acodeh::{fs::FileSearcher, ollama, prompt::PromptBuilder};
+use acodeh::ollama::{
+    GenerateRequest, KeepAlive, LLMClient, LLMError, ModelParameters, ResponseStats,
+};
+use acodeh::prompt::{
+    ChunkStrategy, DEFAULT_TABLE_PREVIEW_ROWS, FileOrder, PromptBuilder, PromptStats,
+};
+use acodeh::{
+    analyze::{ContextLoader, DEFAULT_SYSTEM_PROMPT},
+    config::Config,
+    ollama,
+};
 use anyhow::anyhow;
-use clap::Parser;
+use clap::{Args, CommandFactory, Parser, ValueEnum};
 use futures::StreamExt;
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use std::io::Write;
-use std::path::PathBuf;
+use notify::Watcher;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use tokio_util::sync::CancellationToken;
+
+const MIN_SUMMARY_CHUNK_LEN: usize = 4 * 1024;
+const DEFAULT_MODEL: &str = "llama3.2:latest";
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+const STREAM_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+/// Set on the helper process that keeps serving a copied answer, see [`copy_to_clipboard`].
+const CLIPBOARD_DAEMON_ENV: &str = "ACODEH_CLIPBOARD_DAEMON";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ConfirmDefault {
+    Yes,
+    No,
+}
+
+#[derive(Args, Debug)]
+struct ModelArgs {
+    /// Model to use; falls back to the config file, then to llama3.2:latest. `run`
+    /// takes several (repeated or comma-separated) to compare their answers
+    #[arg(long, env = "ACODEH_MODEL", value_delimiter = ',')]
+    model: Vec<String>,
+    #[arg(long, value_enum, default_value_t = ollama::Backend::Ollama)]
+    backend: ollama::Backend,
+    #[arg(long, env = "ACODEH_API_URL")]
+    api_url: Option<String>,
+    #[arg(long)]
+    system: Option<String>,
+    #[arg(long)]
+    system_file: Option<PathBuf>,
+    #[arg(long)]
+    temperature: Option<f32>,
+    #[arg(long)]
+    top_p: Option<f32>,
+    #[arg(long)]
+    top_k: Option<u32>,
+    #[arg(long)]
+    seed: Option<i64>,
+    #[arg(long)]
+    repeat_penalty: Option<f32>,
+    #[arg(long, allow_negative_numbers = true)]
+    repeat_last_n: Option<i64>,
+    /// Mirostat sampling: 0 (off), 1 or 2
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=2))]
+    mirostat: Option<u8>,
+    #[arg(long)]
+    mirostat_tau: Option<f32>,
+    #[arg(long)]
+    mirostat_eta: Option<f32>,
+    #[arg(long, allow_negative_numbers = true)]
+    num_predict: Option<i64>,
+    #[arg(long, allow_negative_numbers = true)]
+    keep_alive: Option<KeepAlive>,
+    /// Force the answer to be JSON: `json`, or a JSON schema the answer must follow
+    #[arg(long, value_parser = parse_format)]
+    format: Option<serde_json::Value>,
+    /// Stop generating once the output contains this text (repeatable)
+    #[arg(long = "stop")]
+    stop_sequences: Vec<String>,
+    #[arg(long)]
+    timeout: Option<u64>,
+    #[arg(long, default_value_t = 0)]
+    retries: usize,
+    /// Extra header sent with every API request, as `Name: value`
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    #[arg(long, env = "ACODEH_BEARER_TOKEN", hide_env_values = true)]
+    bearer_token: Option<String>,
+    /// Proxy for the API requests, overriding HTTP_PROXY/HTTPS_PROXY
+    #[arg(long, env = "ACODEH_PROXY")]
+    proxy: Option<String>,
+    /// Connect to the API directly, ignoring any proxy settings
+    #[arg(long, default_value_t = false, conflicts_with = "proxy")]
+    no_proxy: bool,
+    /// Also trust this CA certificate (PEM or DER) for HTTPS API URLs
+    #[arg(long, env = "ACODEH_CA_CERT")]
+    ca_cert: Option<PathBuf>,
+    /// Accept invalid or self-signed TLS certificates; for testing only
+    #[arg(long, default_value_t = false)]
+    insecure: bool,
+}
+
+async fn edit_prompt() -> anyhow::Result<String> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // Created exclusively under a random name, and removed when dropped.
+    let file = tempfile::Builder::new()
+        .prefix("acodeh-prompt-")
+        .suffix(".md")
+        .tempfile()?;
+    let path = file.path();
+
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .await;
+    let prompt = tokio::fs::read_to_string(path).await;
+    drop(file);
+
+    let status = status.map_err(|error| anyhow!("Could not run editor {editor:?}: {error}"))?;
+    if !status.success() {
+        return Err(anyhow!("Editor {editor:?} exited with {status}; aborting"));
+    }
+    let prompt = prompt?;
+    if prompt.trim().is_empty() {
+        return Err(anyhow!("Empty prompt; aborting"));
+    }
+    Ok(prompt)
+}
+
+fn confirm_send(
+    prompt_stats: &PromptStats,
+    model: &str,
+    default: ConfirmDefault,
+) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(default == ConfirmDefault::Yes);
+    }
+
+    eprintln!("{:#?}", prompt_stats);
+    eprint!(
+        "Send {} tokens to {model}? [y/N] ",
+        prompt_stats.prompt_context_size_estimated
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(&answer.trim().to_lowercase()[..], "y" | "yes"))
+}
+
+/// Opens the --log-stream file for appending, first moving it to `<file>.1`
+/// (replacing any older one) once it outgrows [`STREAM_LOG_MAX_BYTES`].
+fn open_stream_log(path: &Path) -> std::io::Result<std::fs::File> {
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.len() > STREAM_LOG_MAX_BYTES) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        std::fs::rename(path, rotated)?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+fn warn_context_overflow(prompt_stats: &PromptStats) {
+    if prompt_stats.prompt_context_size_estimated > prompt_stats.max_context {
+        eprintln!(
+            "Warning: the prompt is ~{} tokens but num_ctx is {}; the model will likely cut it short (raise --max-context or send fewer files)",
+            prompt_stats.prompt_context_size_estimated, prompt_stats.max_context
+        );
+    }
+}
+
+fn with_image_hint(error: impl Into<anyhow::Error>, has_images: bool) -> anyhow::Error {
+    let error = error.into();
+    if has_images {
+        error.context("Request with images failed; images need a vision model (see --image)")
+    } else {
+        error
+    }
+}
+
+fn progress_bar(completed: u64, total: u64) -> String {
+    const WIDTH: u64 = 30;
+    let filled = (completed.min(total) * WIDTH)
+        .checked_div(total)
+        .unwrap_or(0) as usize;
+    let percent = (completed.min(total) * 100).checked_div(total).unwrap_or(0);
+    format!(
+        "[{}{}] {percent:>3}% {:.1}/{:.1} MB",
+        "#".repeat(filled),
+        "-".repeat(WIDTH as usize - filled),
+        completed as f64 / 1e6,
+        total as f64 / 1e6,
+    )
+}
+
+fn request_error(error: LLMError, model: &str, has_images: bool) -> anyhow::Error {
+    if error.is_model_not_found() {
+        anyhow!(
+            "Model {model:?} is not installed; download it with `acodeh pull {model}` or `ollama pull {model}`"
+        )
+    } else {
+        with_image_hint(error, has_images)
+    }
+}
+
+fn apply_prompt_template(template: &str, prompt: &str) -> String {
+    if template.contains("{prompt}") {
+        template.replace("{prompt}", prompt)
+    } else {
+        format!("{}\n\n{prompt}", template.trim_end())
+    }
+}
+
+/// Warns when generation ended for another reason than a natural stop, e.g. the
+/// `length` Ollama reports once --num-predict or num_ctx runs out.
+fn warn_done_reason(done_reason: &str) {
+    let why = match done_reason {
+        "" | "stop" => return,
+        "length" => "it hit the --num-predict or num_ctx limit",
+        other => other,
+    };
+    eprintln!("Warning: the response was truncated ({why})");
+}
+
+fn split_prompts(content: &str) -> Vec<String> {
+    let prompts = if content.lines().any(|line| line.trim() == "---") {
+        content
+            .lines()
+            .collect::<Vec<_>>()
+            .split(|line| line.trim() == "---")
+            .map(|block| block.join("\n"))
+            .collect::<Vec<_>>()
+    } else {
+        content.lines().map(str::to_string).collect()
+    };
+    prompts
+        .into_iter()
+        .map(|prompt| prompt.trim().to_string())
+        .filter(|prompt| !prompt.is_empty())
+        .collect()
+}
+
+fn parse_format(value: &str) -> Result<serde_json::Value, String> {
+    if value.trim_start().starts_with('{') {
+        serde_json::from_str(value).map_err(|error| format!("invalid JSON schema: {error}"))
+    } else {
+        Ok(serde_json::Value::String(value.to_string()))
+    }
+}
+
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once(':')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .ok_or_else(|| format!("expected `Name: value`, got {value:?}"))
+}
+
+#[derive(Args, Debug)]
+struct ContextArgs {
+    #[arg(long)]
+    path: Vec<PathBuf>,
+    /// Only add paths matching this glob (`**/*.rs`) or containing this text; prefix
+    /// with `!` to reject matches
+    #[arg(long)]
+    includes: Vec<PathBuf>,
+    /// Skip paths matching this glob (`**/target/**`) or containing this text; wins
+    /// over --includes, and a `!` entry keeps paths other excludes would drop
+    #[arg(long)]
+    excludes: Vec<PathBuf>,
+    #[arg(long)]
+    extensions: Option<String>,
+    /// Skip files with these comma-separated extensions (`lock,min.js`), even when
+    /// --extensions lists them
+    #[arg(long)]
+    exclude_ext: Option<String>,
+    /// List directories after their contents; with `run`, also summarize every file
+    /// first when they don't all fit the context, then answer from the summaries
+    #[arg(long)]
+    overall: bool,
+    #[arg(long, default_value_t = false)]
+    tree: bool,
+    /// Add exactly the files listed in this file, one per line (`-` reads the list
+    /// from stdin), instead of scanning --path; `#` lines are comments
+    #[arg(long, conflicts_with_all = ["staged", "modified"])]
+    files_from: Option<PathBuf>,
+    /// Only add the files staged in git, limited to the --path entries if any
+    #[arg(long, default_value_t = false, conflicts_with = "modified")]
+    staged: bool,
+    /// Only add the files with uncommitted changes, untracked ones included, limited
+    /// to the --path entries if any
+    #[arg(long, default_value_t = false)]
+    modified: bool,
+    /// Send the `git diff` of the --path entries instead of the files themselves
+    #[arg(long, default_value_t = false)]
+    diff: bool,
+    /// Scan every level below each --path, ignoring --max-depth
+    #[arg(short, long)]
+    recursive: bool,
+    /// Descend into symlinked directories and read symlinked files; loops are
+    /// detected and skipped
+    #[arg(long, default_value_t = false)]
+    follow_links: bool,
+    /// Scan dotfiles and dot-directories such as `.env` or `.config/`; pass
+    /// `--include-hidden=false` to skip them
+    #[arg(long, default_value_t = true, num_args = 0..=1, default_missing_value = "true", action = clap::ArgAction::Set)]
+    include_hidden: bool,
+    /// How many directory levels below each --path to scan: 1 reads only the files
+    /// directly inside it, 2 also reads its subdirectories' files, and so on
+    #[arg(long, default_value_t = 1)]
+    max_depth: usize,
+    /// Context size (num_ctx) to use instead of the automatic estimate
+    #[arg(long)]
+    max_context: Option<u64>,
+    /// Upper bound for the automatic context size estimate [default: the model's
+    /// context length, or 16384 when it can't be read]
+    #[arg(long)]
+    max_context_ceiling: Option<u64>,
+    /// Skip files larger than this many bytes without reading them
+    #[arg(long)]
+    max_file_bytes: Option<u64>,
+    /// Stop adding files once this many made it into the prompt
+    #[arg(long)]
+    max_files: Option<usize>,
+    /// Keep the beginning of files that don't fit instead of skipping them; same as
+    /// `--chunk-strategy first-fit`
+    #[arg(long, default_value_t = false, conflicts_with = "chunk_strategy")]
+    truncate_files: bool,
+    /// What to do with files that don't fit the remaining context
+    #[arg(long, value_enum, default_value_t = ChunkStrategy::Whole)]
+    chunk_strategy: ChunkStrategy,
+    #[arg(long, default_value_t = false)]
+    strip_comments: bool,
+    /// Send only the declarations and doc comments of Rust files, with function
+    /// bodies elided as `{ ... }`
+    #[arg(long, default_value_t = false)]
+    definitions_only: bool,
+    /// Send Markdown files as plain text, without link URLs, images or markup
+    #[arg(long, default_value_t = false)]
+    plain_markdown: bool,
+    /// Number the lines of each file; CSV/TSV previews are sent unnumbered
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["strip_comments", "definitions_only", "plain_markdown"]
+    )]
+    line_numbers: bool,
+    /// Rows of each CSV/TSV file, or of each spreadsheet sheet, to include below its
+    /// header
+    #[arg(long, default_value_t = DEFAULT_TABLE_PREVIEW_ROWS)]
+    csv_rows: usize,
+    /// Order of the files in the prompt; the last ones end up closest to the question
+    #[arg(long, value_enum, default_value_t = FileOrder::Insertion)]
+    order_by: FileOrder,
+    #[arg(long, default_value_t = false)]
+    lossy_decode: bool,
+    /// Attach an image for vision models such as llava, llama3.2-vision, gemma3 or
+    /// qwen2.5vl; repeat to attach several
+    #[arg(long = "image")]
+    images: Vec<PathBuf>,
+    /// Add this file's text as a document rather than a source file, e.g. an error
+    /// log or a spec; repeat to add several
+    #[arg(long = "context-file")]
+    context_files: Vec<PathBuf>,
+    /// Keep the markdown cells of Jupyter notebooks, not just the code cells
+    #[arg(long, default_value_t = false)]
+    notebook_markdown: bool,
+    /// Add each file's size and modification time under its path
+    #[arg(long, default_value_t = false)]
+    file_metadata: bool,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 enum Command {
     Run {
-        prompt: String,
-        #[arg(long)]
-        model: Option<String>,
-        #[arg(long)]
-        path: Vec<PathBuf>,
-        #[arg(long)]
-        includes: Vec<PathBuf>,
+        /// The question; `-` reads it from stdin and `@edit` opens $EDITOR
+        #[arg(required_unless_present_any = ["prompt_file", "edit"])]
+        prompt: Option<String>,
+        /// Run each line of this file, or each block separated by a `---` line, as a
+        /// separate prompt against the same files
+        #[arg(long, conflicts_with = "prompt")]
+        prompt_file: Option<PathBuf>,
+        /// Reusable preamble put before each prompt; a `{prompt}` placeholder in it marks
+        /// where the prompt goes instead
         #[arg(long)]
-        excludes: Vec<PathBuf>,
-        #[arg(long)]
-        extensions: Option<String>,
-        #[arg(long)]
-        overall: bool,
+        prompt_template: Option<PathBuf>,
+        /// Write the prompt in $VISUAL or $EDITOR (default vi)
+        #[arg(long, default_value_t = false, conflicts_with_all = ["prompt", "prompt_file"])]
+        edit: bool,
+        #[command(flatten)]
+        model_args: ModelArgs,
+        #[command(flatten)]
+        context_args: ContextArgs,
+        #[arg(long, default_value_t = false)]
+        debug: bool,
+        #[arg(long, default_value_t = false)]
+        show_stats: bool,
+        /// Print the prompt and response stats as one JSON line on stderr
+        #[arg(long, default_value_t = false)]
+        stats_json: bool,
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+        /// Show the payload stats and ask before sending the prompt
+        #[arg(long, default_value_t = false)]
+        confirm: bool,
+        /// Answer to --confirm when stdin is not a terminal
+        #[arg(long, value_enum, default_value_t = ConfirmDefault::No)]
+        confirm_default: ConfirmDefault,
+        /// Print only the answer: turns off --debug, --show-stats, progress output and
+        /// the batch and --model comparison headers
+        #[arg(short, long, default_value_t = false)]
+        quiet: bool,
+        /// Also write the answer to this file, creating parent directories as needed
         #[arg(short, long)]
-        recursive: bool,
-        #[arg(long, default_value_t = 1)]
-        max_depth: usize,
+        output: Option<PathBuf>,
+        /// Overwrite the --output file if it already exists
+        #[arg(long, default_value_t = false, requires = "output")]
+        force: bool,
+        /// Also copy the answer to the system clipboard
+        #[arg(long, default_value_t = false)]
+        clipboard: bool,
+        /// Send the prompt exactly as given, bypassing the model's prompt template and
+        /// the system prompt; takes no files (--path, --files-from, --staged, --diff, ...)
+        /// since there is no `<files>` wrapping to put them in
+        #[arg(
+            long,
+            default_value_t = false,
+            conflicts_with_all = [
+                "path", "context_files", "files_from", "staged", "modified", "diff", "tree",
+            ]
+        )]
+        raw: bool,
+        /// Retry once when the model finishes without any output, instead of failing
+        #[arg(long, default_value_t = false)]
+        retry_empty: bool,
+        /// Wait for the complete answer instead of streaming it
+        #[arg(long, default_value_t = false)]
+        no_stream: bool,
+        /// Also append every streamed chunk, with its timestamp and the milliseconds
+        /// since the request started, to this file as JSON lines; a file over 10 MiB
+        /// is moved to `<file>.1` first
         #[arg(long)]
-        max_context: Option<u64>,
+        log_stream: Option<PathBuf>,
+        /// Model for the per-file summaries of --overall, e.g. a smaller, faster one
+        #[arg(long, requires = "overall")]
+        map_model: Option<String>,
+        /// Keep watching the scanned files after answering and run again, on a cleared
+        /// screen, whenever one of them changes
+        #[arg(long, default_value_t = false, conflicts_with_all = ["dry_run", "confirm"])]
+        watch: bool,
+        /// How many of several --model to query at once; 1 queries them one after the
+        /// other, which suits a single GPU
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        parallel_models: u16,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+    },
+    Chat {
+        #[command(flatten)]
+        model_args: ModelArgs,
+        #[command(flatten)]
+        context_args: ContextArgs,
         #[arg(long, default_value_t = false)]
         debug: bool,
+    },
+    /// Print what the API reports about a model: size, context length, template, ...
+    Show {
+        /// Model to inspect [default: --model, or the configured model]
+        name: Option<String>,
+        #[command(flatten)]
+        model_args: ModelArgs,
+    },
+    /// Download a model, showing the progress
+    Pull {
+        /// Model to download [default: --model, or the configured model]
+        name: Option<String>,
+        #[command(flatten)]
+        model_args: ModelArgs,
+    },
+    /// Estimate the tokens the context takes and whether it fits, without contacting
+    /// the model
+    Count {
+        /// Question to count along with the files
+        prompt: Option<String>,
+        #[command(flatten)]
+        context_args: ContextArgs,
+        /// Exit with an error when the context doesn't fit the budget
         #[arg(long, default_value_t = false)]
-        show_stats: bool,
+        strict: bool,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
     },
+    /// Print a shell completion script to stdout
+    Completions { shell: clap_complete::Shell },
+}
+
+/// Reads one path per line from `source` (`-` for stdin), skipping blank lines and
+/// `#` comments; listed files that don't exist are reported and left out.
+async fn read_files_list(source: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let content = if source == Path::new("-") {
+        let mut content = String::new();
+        tokio::io::stdin().read_to_string(&mut content).await?;
+        content
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .map_err(|error| anyhow!("Could not read files list {source:?}: {error}"))?
+    };
+
+    let mut paths = vec![];
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if path.is_file() {
+            paths.push(path);
+        } else {
+            eprintln!("Skipping {path:?} from {source:?}: not a file");
+        }
+    }
+    Ok(paths)
+}
+
+/// The scan --path, --files-from, --staged, ... describe; reads the --files-from list.
+async fn context_loader(args: &ContextArgs, config: &Config) -> anyhow::Result<ContextLoader> {
+    let files = match &args.files_from {
+        Some(files_from) => Some(read_files_list(files_from).await?),
+        None => None,
+    };
+    Ok(ContextLoader::default()
+        .paths(args.path.clone())
+        .files(files)
+        .staged(args.staged)
+        .modified(args.modified)
+        .includes(args.includes.clone())
+        .excludes(args.excludes.clone())
+        .extensions(args.extensions.clone().or(config.extensions.clone()))
+        .exclude_extensions(args.exclude_ext.clone())
+        .max_depth(if args.recursive {
+            usize::MAX
+        } else {
+            args.max_depth
+        })
+        .overall(args.overall)
+        .follow_links(args.follow_links)
+        .include_hidden(args.include_hidden)
+        .tree(args.tree)
+        .diff(args.diff)
+        .images(args.images.clone())
+        .context_files(args.context_files.clone()))
+}
+
+/// Size and modification time of every file `load_context` would read, to tell
+/// real changes from events on ignored files (build output, `.git`, ...).
+async fn context_fingerprint(
+    args: &ContextArgs,
+    config: &Config,
+) -> anyhow::Result<Vec<(PathBuf, u64, Option<std::time::SystemTime>)>> {
+    let context_loader = context_loader(args, config).await?;
+    let paths = context_loader.scan()?.filter_map(Result::ok);
+    Ok(paths
+        .map(|path| {
+            let metadata = std::fs::metadata(&path).ok();
+            let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+            let modified = metadata.and_then(|metadata| metadata.modified().ok());
+            (path, size, modified)
+        })
+        .collect())
+}
+
+/// Waits until a file of the context differs from `fingerprint`, letting bursts of
+/// events settle for `WATCH_DEBOUNCE` first; returns `false` on Ctrl+C.
+async fn wait_for_changes(
+    args: &ContextArgs,
+    config: &Config,
+    fingerprint: &[(PathBuf, u64, Option<std::time::SystemTime>)],
+) -> anyhow::Result<bool> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|event| !event.kind.is_access()) {
+            let _ = sender.send(());
+        }
+    })?;
+    let mut roots = if args.path.is_empty() {
+        vec![PathBuf::from(".")]
+    } else {
+        args.path.clone()
+    };
+    roots.extend(args.files_from.clone());
+    for root in &roots {
+        watcher
+            .watch(root, notify::RecursiveMode::Recursive)
+            .map_err(|error| anyhow!("Could not watch {root:?}: {error}"))?;
+    }
+
+    if context_fingerprint(args, config).await? != fingerprint {
+        return Ok(true);
+    }
+    let ctrl_c = cancel_on_ctrl_c();
+    loop {
+        tokio::select! {
+            _ = receiver.recv() => {}
+            _ = ctrl_c.token().cancelled() => return Ok(false),
+        }
+        while let Ok(Some(())) = tokio::time::timeout(WATCH_DEBOUNCE, receiver.recv()).await {}
+        if context_fingerprint(args, config).await? != fingerprint {
+            return Ok(true);
+        }
+    }
+}
+
+async fn load_context(
+    prompt: String,
+    args: &ContextArgs,
+    config: &Config,
+    model_context_length: Option<u64>,
+    debug: bool,
+    progress: bool,
+) -> anyhow::Result<PromptBuilder> {
+    let max_context = args.max_context.or(config.max_context());
+    let max_context_ceiling = args
+        .max_context_ceiling
+        .or(config.max_context_ceiling)
+        .or(model_context_length);
+    let context_loader = context_loader(args, config).await?;
+
+    let mut prompt_builder = PromptBuilder::new(prompt)
+        .max_context(max_context)
+        .max_context_ceiling(max_context_ceiling)
+        .max_files(args.max_files)
+        .max_file_bytes(args.max_file_bytes)
+        .chunk_strategy(args.chunk_strategy)
+        .truncate_files(args.truncate_files)
+        .strip_comments(args.strip_comments)
+        .definitions_only(args.definitions_only)
+        .plain_markdown(args.plain_markdown)
+        .line_numbers(args.line_numbers)
+        .table_preview_rows(args.csv_rows)
+        .order_by(args.order_by)
+        .lossy_decode(args.lossy_decode)
+        .notebook_markdown(args.notebook_markdown)
+        .file_metadata(args.file_metadata);
+
+    let mut scanned_files = 0;
+    let mut added_files = vec![];
+    let errors = context_loader
+        .load(&mut prompt_builder, |prompt_builder, path, result| {
+            scanned_files += 1;
+            match result {
+                Ok(added) if debug => added_files.push((path.to_path_buf(), added.clone())),
+                Err(err) if debug => eprintln!("{err:?}"),
+                _ => {}
+            }
+            if progress {
+                eprint!(
+                    "\rLoading files: {scanned_files} scanned, {} added",
+                    prompt_builder.files().len()
+                );
+            }
+        })
+        .await?;
+    if debug {
+        for error in &errors {
+            eprintln!("{error:?}");
+        }
+    }
+    if progress && scanned_files > 0 {
+        eprint!("\r\x1b[2K");
+    }
+
+    if debug {
+        println!("{:#^80}", " Debugging context added ");
+        let mut total_tokens = 0;
+        for (path, added) in &added_files {
+            total_tokens += added.est_tokens;
+            println!(
+                "File {path:?} ({}, {}b, ~{} tokens, ~{total_tokens} total{}) added",
+                added.language,
+                added.bytes,
+                added.est_tokens,
+                if added.truncated { ", truncated" } else { "" }
+            );
+        }
+        for (index, document) in prompt_builder.documents().iter().enumerate() {
+            let tokens = prompt_builder.count_tokens(document);
+            total_tokens += tokens;
+            println!(
+                "Document {} ({}b, ~{tokens} tokens, ~{total_tokens} total) added",
+                index + 1,
+                document.len()
+            );
+        }
+        for path in prompt_builder.lossy_files() {
+            println!("Lossy UTF-8 decoding used for {path:?}");
+        }
+        println!("{:#^80}\n", "");
+    }
+
+    Ok(prompt_builder)
+}
+
+async fn system_prompt(args: &ModelArgs) -> anyhow::Result<String> {
+    Ok(match (&args.system, &args.system_file) {
+        (Some(system), _) => system.clone(),
+        (None, Some(system_file)) => tokio::fs::read_to_string(system_file)
+            .await
+            .map_err(|error| anyhow!("Could not read system file {system_file:?}: {error}"))?,
+        (None, None) => DEFAULT_SYSTEM_PROMPT.to_string(),
+    })
+}
+
+fn build_client(args: &ModelArgs, config: &Config) -> anyhow::Result<LLMClient> {
+    let api_url = args
+        .api_url
+        .clone()
+        .or(config.api_url.clone())
+        .unwrap_or_else(|| args.backend.default_api_url().to_string());
+    Ok(LLMClient::builder(&api_url)
+        .backend(args.backend)
+        .timeout(args.timeout.map(Duration::from_secs))
+        .retries(args.retries)
+        .headers(args.headers.clone())
+        .bearer_token(args.bearer_token.clone())
+        .proxy(args.proxy.clone())
+        .no_proxy(args.no_proxy)
+        .ca_cert(args.ca_cert.clone())
+        .insecure(args.insecure)
+        .build()?)
+}
+
+async fn summarize_context(
+    user_prompt: &str,
+    prompt_builder: &PromptBuilder,
+    prompt_stats: &PromptStats,
+    client: &LLMClient,
+    model: &str,
+    options: ModelParameters,
+    progress: bool,
+) -> anyhow::Result<PromptBuilder> {
+    let mut contents = prompt_builder
+        .files()
+        .iter()
+        .map(|(path, content)| (path.clone(), content.clone()))
+        .collect::<Vec<_>>();
+    let reader = prompt_builder.reader();
+    for path in &prompt_stats.skipped_files {
+        match reader.read(path.clone()).await {
+            Ok(file) => contents.push((
+                path.clone(),
+                format!("path: {}\n{}", path.display(), file.content),
+            )),
+            Err(error) => eprintln!("Could not read {path:?} to summarize it: {error}"),
+        }
+    }
+
+    let chunk_len = (prompt_stats.max_context as usize)
+        .saturating_mul(2)
+        .max(MIN_SUMMARY_CHUNK_LEN);
+    let mut summarized_builder = PromptBuilder::new(user_prompt.to_string())
+        .max_context_ceiling(Some(prompt_stats.max_context));
+    for document in prompt_builder.documents() {
+        summarized_builder.add_document(document.clone())?;
+    }
+    for (index, (path, content)) in contents.iter().enumerate() {
+        if progress {
+            eprint!(
+                "\r\x1b[2KSummarizing {}/{}: {}",
+                index + 1,
+                contents.len(),
+                path.display()
+            );
+        }
+        let mut summaries = vec![];
+        let mut rest = &content[..];
+        while !rest.is_empty() {
+            let (chunk, next) = rest.split_at(
+                rest.floor_char_boundary(chunk_len)
+                    .max(rest.chars().next().map_or(rest.len(), char::len_utf8)),
+            );
+            rest = next;
+            let response = GenerateRequest::new(model, client)
+                .options(options.clone())
+                .num_ctx_options(prompt_stats.max_context)
+                .prompt(&format!(
+                    include_str!("summary_prompt.in"),
+                    user_prompt, chunk
+                ))
+                .await
+                .map_err(|error| request_error(error, model, false))?;
+            summaries.push(response.response.trim().to_string());
+        }
+        let summary = format!(
+            "<summary path=\"{}\">\n{}\n</summary>",
+            path.display(),
+            summaries.join("\n")
+        );
+        if let Err(error) = summarized_builder.add_document(summary) {
+            eprintln!("{error}");
+        }
+    }
+    if progress && !contents.is_empty() {
+        eprint!("\r\x1b[2K");
+    }
+
+    Ok(summarized_builder)
+}
+
+async fn model_context_length(
+    client: &LLMClient,
+    model: &str,
+    args: &ContextArgs,
+    config: &Config,
+    debug: bool,
+) -> Option<u64> {
+    if args.max_context.or(config.max_context()).is_some()
+        || args
+            .max_context_ceiling
+            .or(config.max_context_ceiling)
+            .is_some()
+    {
+        return None;
+    }
+    match client.context_length(model).await {
+        Ok(context_length) => context_length,
+        Err(error) => {
+            if debug {
+                eprintln!("Could not read the context length of {model}: {error}");
+            }
+            None
+        }
+    }
+}
+
+/// Resolves the model: --model, then ACODEH_MODEL (both through clap), then the
+/// config file, then the built-in default.
+fn model_name(args: &ModelArgs, config: &Config) -> String {
+    args.model
+        .first()
+        .cloned()
+        .or(config.model.clone())
+        .unwrap_or(DEFAULT_MODEL.to_string())
+}
+
+fn single_model(args: &ModelArgs, command: &str) -> anyhow::Result<()> {
+    if args.model.len() > 1 {
+        return Err(anyhow!(
+            "{command} takes a single --model; only `run` compares several"
+        ));
+    }
+    Ok(())
+}
+
+/// Sends the same prompt to every model, `parallel` at a time, and prints each
+/// answer under a header with its token rates; returns the printed answers.
+async fn compare_models(
+    requests: Vec<(String, GenerateRequest<'_>)>,
+    prompt: &str,
+    parallel: usize,
+    has_images: bool,
+    output_format: OutputFormat,
+    quiet: bool,
+) -> String {
+    let mut output_text = String::new();
+    let mut responses = futures::stream::iter(requests)
+        .map(|(model, request)| async move { (model, request.prompt(prompt).await) })
+        .buffered(parallel.max(1));
+    while let Some((model, result)) = responses.next().await {
+        let header = format!(" {model} ");
+        let response = result
+            .map_err(|error| request_error(error, &model, has_images))
+            .and_then(|response| match &response.error {
+                Some(error) => Err(with_image_hint(anyhow!("LLM error: {error}"), has_images)),
+                None => Ok(response),
+            });
+        match (&response, output_format) {
+            (Ok(response), OutputFormat::Json) => println!(
+                "{}",
+                serde_json::json!({
+                    "model": model,
+                    "response": response.response,
+                    "response_stats": ResponseStats::from(response),
+                })
+            ),
+            (Err(error), OutputFormat::Json) => println!(
+                "{}",
+                serde_json::json!({ "model": model, "error": format!("{error:#}") })
+            ),
+            (Ok(response), OutputFormat::Text) if quiet => println!("{}\n", response.response),
+            (Err(error), OutputFormat::Text) if quiet => eprintln!("{model}: Error: {error:#}"),
+            (Ok(response), OutputFormat::Text) => {
+                let stats = ResponseStats::from(response);
+                println!("{header:=^80}");
+                println!("{}", response.response);
+                println!(
+                    "\n[eval tokens/sec: {}, prompt tokens/sec: {}, total: {:?}]\n",
+                    stats.eval_rate(),
+                    stats.prompt_rate(),
+                    Duration::from_nanos(response.total_duration)
+                );
+            }
+            (Err(error), OutputFormat::Text) => {
+                println!("{header:=^80}");
+                println!("Error: {error:#}\n");
+            }
+        }
+        if let Ok(response) = &response {
+            warn_done_reason(&response.done_reason);
+            output_text.push_str(&format!("{header:=^80}\n{}\n\n", response.response));
+        }
+    }
+    output_text
+}
+
+fn model_parameters(args: &ModelArgs, config: &Config) -> ModelParameters {
+    ModelParameters {
+        temperature: args.temperature.or(config.options.temperature),
+        top_p: args.top_p.or(config.options.top_p),
+        top_k: args.top_k.or(config.options.top_k),
+        seed: args.seed.or(config.options.seed),
+        repeat_penalty: args.repeat_penalty.or(config.options.repeat_penalty),
+        repeat_last_n: args.repeat_last_n.or(config.options.repeat_last_n),
+        mirostat: args.mirostat.or(config.options.mirostat),
+        mirostat_tau: args.mirostat_tau.or(config.options.mirostat_tau),
+        mirostat_eta: args.mirostat_eta.or(config.options.mirostat_eta),
+        num_predict: args.num_predict.or(config.options.num_predict),
+        ..Default::default()
+    }
+}
+
+/// The token the next Ctrl+C cancels, tagged with the id of the guard that registered it.
+static CTRL_C_TOKEN: std::sync::Mutex<Option<(u64, CancellationToken)>> =
+    std::sync::Mutex::new(None);
+
+/// Unregisters its token from the Ctrl+C listener when dropped.
+struct CtrlCGuard {
+    id: u64,
+    cancellation_token: CancellationToken,
+}
+
+impl CtrlCGuard {
+    fn token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+}
+
+impl Drop for CtrlCGuard {
+    fn drop(&mut self) {
+        let mut current = CTRL_C_TOKEN.lock().unwrap();
+        if current.as_ref().is_some_and(|(id, _)| *id == self.id) {
+            *current = None;
+        }
+    }
+}
+
+/// Returns a token the next Ctrl+C cancels while the guard lives. Once tokio
+/// listens for SIGINT it does for the rest of the process, so a single listener
+/// handles every Ctrl+C and exits with 130 when no token is registered, including
+/// a second Ctrl+C while the first one's stream winds down.
+fn cancel_on_ctrl_c() -> CtrlCGuard {
+    static LISTENER: std::sync::Once = std::sync::Once::new();
+    static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    LISTENER.call_once(|| {
+        tokio::spawn(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                match CTRL_C_TOKEN.lock().unwrap().take() {
+                    Some((_, cancellation_token)) => cancellation_token.cancel(),
+                    None => std::process::exit(130),
+                }
+            }
+        });
+    });
+    let guard = CtrlCGuard {
+        id: NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        cancellation_token: CancellationToken::new(),
+    };
+    *CTRL_C_TOKEN.lock().unwrap() = Some((guard.id, guard.cancellation_token.clone()));
+    guard
+}
+
+/// Copies `text` to the system clipboard. X11 and Wayland clipboards are served by
+/// the process that set them, so on Linux a detached copy of acodeh keeps serving
+/// the answer until something else is copied.
+fn copy_to_clipboard(text: &str) -> anyhow::Result<()> {
+    // Fails early, and visibly, when there is no clipboard to talk to.
+    let mut clipboard = arboard::Clipboard::new()?;
+    if cfg!(target_os = "linux") {
+        drop(clipboard);
+        let mut daemon = std::process::Command::new(std::env::current_exe()?)
+            .env(CLIPBOARD_DAEMON_ENV, "1")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+        daemon
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(text.as_bytes())?;
+    } else {
+        clipboard.set_text(text)?;
+    }
+    Ok(())
+}
+
+/// The helper process behind [`copy_to_clipboard`]: sets the clipboard to its stdin
+/// and serves it until it is replaced.
+#[cfg(target_os = "linux")]
+fn serve_clipboard() -> anyhow::Result<()> {
+    use arboard::SetExtLinux;
+    use std::io::Read;
+
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text)?;
+    arboard::Clipboard::new()?.set().wait().text(text)?;
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    #[cfg(target_os = "linux")]
+    if std::env::var_os(CLIPBOARD_DAEMON_ENV).is_some() {
+        return serve_clipboard();
+    }
     let command = Command::parse();
+    let config = Config::load()?;
 
     match command {
         Command::Run {
-            model,
             prompt,
-            path,
-            includes,
-            excludes,
-            extensions,
-            overall,
-            recursive,
-            max_depth,
-            max_context,
+            prompt_file,
+            prompt_template,
+            edit,
+            model_args,
+            context_args,
             debug,
             show_stats,
+            stats_json,
+            dry_run,
+            confirm,
+            confirm_default,
+            clipboard,
+            raw,
+            retry_empty,
+            no_stream,
+            log_stream,
+            map_model,
+            watch,
+            parallel_models,
+            quiet,
+            output,
+            force,
+            output_format,
         } => {
+            let debug = debug && !quiet;
+            let show_stats = show_stats && !quiet;
+
+            if prompt.as_deref() == Some("-")
+                && context_args.files_from.as_deref() == Some(Path::new("-"))
+            {
+                return Err(anyhow!(
+                    "The prompt and --files-from can't both be read from stdin"
+                ));
+            }
+            if watch && context_args.files_from.as_deref() == Some(Path::new("-")) {
+                return Err(anyhow!("--watch can't re-read --files-from from stdin"));
+            }
+            if raw && model_args.backend != ollama::Backend::Ollama {
+                return Err(anyhow!("--raw is only supported by the ollama backend"));
+            }
+            if model_args.model.len() > 1
+                && let Some((flag, _)) = [
+                    ("--format", model_args.format.is_some()),
+                    ("--stats-json", stats_json),
+                    ("--log-stream", log_stream.is_some()),
+                    ("--retry-empty", retry_empty),
+                ]
+                .into_iter()
+                .find(|(_, set)| *set)
+            {
+                return Err(anyhow!("{flag} takes a single --model"));
+            }
+            if let Some(output) = &output
+                && output.exists()
+                && !force
+            {
+                return Err(anyhow!(
+                    "Output file {output:?} already exists; pass --force to overwrite it"
+                ));
+            }
+
+            let prompts = match (prompt_file, prompt) {
+                (Some(prompt_file), _) => {
+                    let content =
+                        tokio::fs::read_to_string(&prompt_file)
+                            .await
+                            .map_err(|error| {
+                                anyhow!("Could not read prompt file {prompt_file:?}: {error}")
+                            })?;
+                    let prompts = split_prompts(&content);
+                    if prompts.is_empty() {
+                        return Err(anyhow!("No prompts found in {prompt_file:?}"));
+                    }
+                    prompts
+                }
+                (None, Some(prompt)) if prompt == "-" => {
+                    let mut prompt = String::new();
+                    tokio::io::stdin().read_to_string(&mut prompt).await?;
+                    if prompt.trim().is_empty()
+                        && context_args.path.is_empty()
+                        && context_args.files_from.is_none()
+                    {
+                        return Err(anyhow!(
+                            "Empty prompt read from stdin and no --path given; pipe a prompt (e.g. `git diff | acodeh run -`) or pass files with --path"
+                        ));
+                    }
+                    vec![prompt]
+                }
+                (None, Some(prompt)) if prompt == "@edit" => vec![edit_prompt().await?],
+                (None, Some(prompt)) => vec![prompt],
+                (None, None) if edit => vec![edit_prompt().await?],
+                (None, None) => return Err(anyhow!("Pass a prompt or --prompt-file")),
+            };
+            let is_batch = prompts.len() > 1;
+            let prompt_template = match &prompt_template {
+                Some(path) => Some(tokio::fs::read_to_string(path).await.map_err(|error| {
+                    anyhow!("Could not read prompt template {path:?}: {error}")
+                })?),
+                None => None,
+            };
+
             if debug {
+                if model_args.model.len() > 1 {
+                    println!("Models: {}", model_args.model.join(", "));
+                } else {
+                    println!("Model: {}", model_name(&model_args, &config));
+                }
                 println!("{:#^80}", " Prompt ");
-                println!("{prompt}");
+                println!("{}", prompts.join("\n---\n"));
                 println!("{:#^80}\n", "");
             }
 
-            let max_depth = if recursive { usize::MAX } else { max_depth };
-
-            let paths_iter = path
-                .iter()
-                .flat_map(|start_path| {
-                    let mut ignore_build = GitignoreBuilder::new(start_path);
-                    if let Err(error) = ignore_build.add_line(None, ".git")
-                        && debug
-                    {
-                        eprintln!("Could not add .git to ignore: {error:?}");
+            let generation = if dry_run {
+                None
+            } else {
+                Some((
+                    system_prompt(&model_args).await?,
+                    build_client(&model_args, &config)?,
+                ))
+            };
+            // Compared models share one prompt, so the smallest window bounds it.
+            let context_length = match &generation {
+                Some((_, client)) if model_args.model.len() > 1 => {
+                    let mut context_length: Option<u64> = None;
+                    for model in &model_args.model {
+                        if let Some(length) =
+                            model_context_length(client, model, &context_args, &config, debug).await
+                        {
+                            context_length =
+                                Some(context_length.map_or(length, |min| min.min(length)));
+                        }
                     }
+                    context_length
+                }
+                Some((_, client)) => {
+                    let model = model_name(&model_args, &config);
+                    model_context_length(client, &model, &context_args, &config, debug).await
+                }
+                None => None,
+            };
+
+            loop {
+                let fingerprint = if watch {
+                    context_fingerprint(&context_args, &config).await?
+                } else {
+                    vec![]
+                };
+                let result: anyhow::Result<()> = async {
+                    let mut prompt_builder = load_context(
+                        prompts.join("\n"),
+                        &context_args,
+                        &config,
+                        context_length,
+                        debug,
+                        !quiet && !debug && std::io::stderr().is_terminal(),
+                    )
+                    .await?;
 
-                    let mut ignore = match ignore_build.build() {
-                        Ok(ignore) => ignore,
-                        Err(error) => {
-                            if debug {
-                                eprintln!("Failed to build ignore patterns: {error:?}");
-                                println!("Using a empty ignore pattern...");
+                    let mut stream_log = match &log_stream {
+                        Some(path) => Some(open_stream_log(path).map_err(|error| {
+                            anyhow!("Could not open stream log {path:?}: {error}")
+                        })?),
+                        None => None,
+                    };
+                    let mut output_text = String::new();
+                    for (index, user_prompt) in prompts.iter().enumerate() {
+                        if is_batch && output_format == OutputFormat::Text && !quiet {
+                            let header = format!(" Prompt {}/{} ", index + 1, prompts.len());
+                            if index > 0 {
+                                println!();
                             }
-                            Gitignore::empty()
+                            println!("{header:=^80}");
+                            println!("{user_prompt}");
+                            println!("{:=^80}\n", "");
                         }
-                    };
 
-                    FileSearcher::new(start_path)
-                        .overall(overall)
-                        .max_depth(max_depth)
-                        .includes(&includes)
-                        .excludes(&excludes)
-                        .extensions(extensions.as_ref())
-                        .into_iter()
-                        .filter_path(move |path| {
-                            if path.ends_with(".gitignore") {
-                                if let Some(error) = ignore_build.add(path)
-                                    && debug
+                        prompt_builder.set_prompt(match &prompt_template {
+                            Some(template) => apply_prompt_template(template, user_prompt),
+                            None => user_prompt.clone(),
+                        });
+                        let (prompt, prompt_stats) = prompt_builder.build()?;
+
+                        if show_stats && output_format == OutputFormat::Text {
+                            println!("{:#^80}", " Payload stats ");
+                            println!("{:#?}", prompt_stats);
+                            println!("{:#^80}\n", "");
+                        }
+
+                        let Some((system, client)) = &generation else {
+                            if stats_json {
+                                eprintln!(
+                                    "{}",
+                                    serde_json::json!({
+                                        "prompt_stats": prompt_stats,
+                                        "response_stats": null,
+                                    })
+                                );
+                            }
+                            println!("{prompt}");
+                            continue;
+                        };
+
+                        let (prompt, prompt_stats) =
+                            if context_args.overall && !prompt_stats.skipped_files.is_empty() {
+                                summarize_context(
+                                    user_prompt,
+                                    &prompt_builder,
+                                    &prompt_stats,
+                                    client,
+                                    &map_model
+                                        .clone()
+                                        .unwrap_or_else(|| model_name(&model_args, &config)),
+                                    model_parameters(&model_args, &config),
+                                    !quiet && std::io::stderr().is_terminal(),
+                                )
+                                .await?
+                                .build()?
+                            } else {
+                                (prompt, prompt_stats)
+                            };
+                        warn_context_overflow(&prompt_stats);
+
+                        if confirm
+                            && !confirm_send(
+                                &prompt_stats,
+                                &if model_args.model.len() > 1 {
+                                    model_args.model.join(", ")
+                                } else {
+                                    model_name(&model_args, &config)
+                                },
+                                confirm_default,
+                            )?
+                        {
+                            return Err(anyhow!("Aborted; nothing was sent"));
+                        }
+
+                        let model = model_name(&model_args, &config);
+                        let has_images = !prompt_builder.images().is_empty();
+                        let new_request = |model| {
+                            let request = GenerateRequest::new(model, client)
+                                .raw(raw)
+                                .options(model_parameters(&model_args, &config))
+                                .keep_alive(model_args.keep_alive.clone())
+                                .stop_sequences(model_args.stop_sequences.clone())
+                                .format(model_args.format.clone())
+                                .images(prompt_builder.images().clone())
+                                .num_ctx_options(prompt_stats.max_context);
+                            if raw { request } else { request.system(system) }
+                        };
+                        if model_args.model.len() > 1 {
+                            let requests = model_args
+                                .model
+                                .iter()
+                                .map(|model| (model.clone(), new_request(model)))
+                                .collect();
+                            let comparison = compare_models(
+                                requests,
+                                &prompt,
+                                parallel_models.into(),
+                                has_images,
+                                output_format,
+                                quiet,
+                            )
+                            .await;
+                            if is_batch {
+                                let header = format!(" Prompt {}/{} ", index + 1, prompts.len());
+                                output_text.push_str(&format!(
+                                    "{header:=^80}\n{user_prompt}\n{:=^80}\n\n",
+                                    ""
+                                ));
+                            }
+                            output_text.push_str(&comparison);
+                            continue;
+                        }
+                        let start_stream = async || {
+                            let request = new_request(&model);
+                            if no_stream {
+                                let response = request
+                                    .prompt(&prompt)
+                                    .await
+                                    .map_err(|error| request_error(error, &model, has_images))?;
+                                anyhow::Ok((futures::stream::iter([response]).left_stream(), None))
+                            } else {
+                                let ctrl_c = cancel_on_ctrl_c();
+                                let stream = request
+                                    .cancellation_token(ctrl_c.token().clone())
+                                    .prompt_stream(&prompt)
+                                    .await
+                                    .map_err(|error| request_error(error, &model, has_images))?
+                                    .right_stream();
+                                Ok((stream, Some(ctrl_c)))
+                            }
+                        };
+                        let started = std::time::Instant::now();
+                        let (mut stream, mut ctrl_c) = start_stream().await?;
+                        let mut retried = false;
+
+                        let mut response_text = String::new();
+                        while let Some(response) = stream.next().await {
+                            if let Some(stream_log) = &mut stream_log {
+                                let timestamp = std::time::SystemTime::now()
+                                    .duration_since(std::time::UNIX_EPOCH)
+                                    .unwrap_or_default();
+                                writeln!(
+                                    stream_log,
+                                    "{}",
+                                    serde_json::json!({
+                                        "timestamp_ms": timestamp.as_millis() as u64,
+                                        "elapsed_ms": started.elapsed().as_millis() as u64,
+                                        "response": response.response,
+                                        "done": response.done,
+                                        "error": response.error,
+                                    })
+                                )?;
+                            }
+                            if let Some(err) = response.error {
+                                return Err(with_image_hint(
+                                    anyhow!("LLM error: {err}"),
+                                    has_images,
+                                ));
+                            }
+
+                            response_text.push_str(&response.response);
+                            if output_format == OutputFormat::Text {
+                                print!("{}", response.response);
+                                std::io::stdout().flush().unwrap();
+                            }
+                            if response.done {
+                                if response_text.trim().is_empty() && response.done_reason == "stop"
+                                {
+                                    if !retry_empty || retried {
+                                        return Err(anyhow!(
+                                            "Model {model:?} returned empty output"
+                                        ));
+                                    }
+                                    eprintln!(
+                                        "Model {model:?} returned empty output; retrying once"
+                                    );
+                                    drop(ctrl_c);
+                                    (stream, ctrl_c) = start_stream().await?;
+                                    retried = true;
+                                    continue;
+                                }
+
+                                if model_args.format.is_some()
+                                    && let Err(error) =
+                                        serde_json::from_str::<serde_json::Value>(&response_text)
                                 {
-                                    eprintln!("ERROR: {}", error);
+                                    if output_format == OutputFormat::Text {
+                                        println!();
+                                    }
+                                    return Err(anyhow!("Model output is not valid JSON: {error}"));
                                 }
-                                ignore = match ignore_build.build() {
-                                    Ok(ignore) => ignore,
-                                    Err(error) => {
-                                        if debug {
-                                            eprintln!("Failed to build ignore patterns: {error:?}");
-                                            println!("Using a empty ignore pattern...");
-                                        }
-                                        Gitignore::empty()
+
+                                if output_format == OutputFormat::Json {
+                                    let mut result = serde_json::json!({
+                                        "response": response_text,
+                                        "model": response.model,
+                                        "done_reason": response.done_reason,
+                                        "eval_count": response.eval_count,
+                                        "prompt_eval_count": response.prompt_eval_count,
+                                        "total_duration": response.total_duration,
+                                        "load_duration": response.load_duration,
+                                        "prompt_eval_duration": response.prompt_eval_duration,
+                                        "eval_duration": response.eval_duration,
+                                        "prompt_stats": prompt_stats,
+                                    });
+                                    if is_batch {
+                                        result["prompt"] = user_prompt.clone().into();
                                     }
-                                };
+                                    println!("{result}");
+                                } else {
+                                    println!();
+                                }
+                                warn_done_reason(&response.done_reason);
+
+                                if show_stats && output_format == OutputFormat::Text {
+                                    let response_stats = ResponseStats::from(&response);
+                                    println!("\n{:#^80}", " Reponse stats ");
+                                    println!("model: {}", response.model);
+                                    println!("eval_count: {}", response.eval_count);
+                                    println!("prompt_eval_count: {}", response.prompt_eval_count);
+                                    println!("eval tokens/sec: {}", response_stats.eval_rate());
+                                    println!("prompt tokens/sec: {}", response_stats.prompt_rate());
+                                    println!("error: {:?}", response.error);
+                                    println!("load phase: {:?}", response_stats.load_phase());
+                                    println!(
+                                        "prompt phase (time to first token): {:?}",
+                                        response_stats.prompt_phase()
+                                    );
+                                    println!(
+                                        "generation phase: {:?}",
+                                        response_stats.generation_phase()
+                                    );
+                                    println!(
+                                        "total_duration: {:?}",
+                                        Duration::from_nanos(response.total_duration)
+                                    );
+                                    println!("{:#^80}", "");
+                                }
+
+                                if stats_json {
+                                    eprintln!(
+                                        "{}",
+                                        serde_json::json!({
+                                            "prompt_stats": prompt_stats,
+                                            "response_stats": ResponseStats::from(&response),
+                                        })
+                                    );
+                                }
+
+                                if debug {
+                                    println!("\n{:#^80}", " Debugging response ");
+                                    println!("{:#?}", response);
+                                    println!("{:#^80}", "");
+                                }
                             }
-                            ignore.matched(path, path.is_dir()).is_none()
-                        })
-                        .filter_map(|result| result.ok())
-                })
-                .filter(|path| path.is_file());
+                        }
+                        drop(ctrl_c);
 
-            let mut prompt_builder = PromptBuilder::new(prompt).max_context(max_context);
-            for path in paths_iter {
-                if let Err(err) = prompt_builder.add_file(path).await {
-                    if debug {
-                        eprintln!("{err:?}");
+                        if is_batch {
+                            let header = format!(" Prompt {}/{} ", index + 1, prompts.len());
+                            output_text.push_str(&format!(
+                                "{header:=^80}\n{user_prompt}\n{:=^80}\n\n",
+                                ""
+                            ));
+                            output_text.push_str(&response_text);
+                            output_text.push_str("\n\n");
+                        } else {
+                            output_text = response_text;
+                        }
                     }
-                    if err.to_string().contains("Maximum context exceeded") {
-                        break;
+
+                    if let Some(output) = &output
+                        && generation.is_some()
+                    {
+                        if let Some(parent) = output.parent()
+                            && !parent.as_os_str().is_empty()
+                        {
+                            tokio::fs::create_dir_all(parent).await?;
+                        }
+                        tokio::fs::write(output, &output_text)
+                            .await
+                            .map_err(|error| {
+                                anyhow!("Could not write output file {output:?}: {error}")
+                            })?;
                     }
-                }
-            }
 
-            let (prompt, prompt_stats) = prompt_builder.build()?;
+                    if clipboard
+                        && generation.is_some()
+                        && let Err(error) = copy_to_clipboard(&output_text)
+                    {
+                        eprintln!("Could not copy the answer to the clipboard: {error}");
+                    }
+                    Ok(())
+                }
+                .await;
 
-            if debug {
-                println!("{:#^80}", " Debugging context added ");
-                for (path, content) in prompt_builder.files() {
-                    println!("File {path:?} ({}b) added", content.len());
+                if !watch {
+                    return result;
                 }
-                println!("{:#^80}\n", "");
+                if let Err(error) = result {
+                    eprintln!("Error: {error:?}");
+                }
+                eprintln!("\nWatching for changes, press Ctrl+C to stop");
+                if !wait_for_changes(&context_args, &config, &fingerprint).await? {
+                    break;
+                }
+                if std::io::stdout().is_terminal() {
+                    print!("\x1b[2J\x1b[H");
+                }
+            }
+        }
+        Command::Chat {
+            model_args,
+            context_args,
+            debug,
+        } => {
+            single_model(&model_args, "chat")?;
+            if model_args.backend != ollama::Backend::Ollama {
+                return Err(anyhow!(
+                    "Chat needs the ollama backend to carry the conversation context"
+                ));
             }
 
-            if show_stats {
-                println!("{:#^80}", " Payload stats ");
-                println!("{:#?}", prompt_stats);
-                println!("{:#^80}\n", "");
+            let system = system_prompt(&model_args).await?;
+            let client = build_client(&model_args, &config)?;
+            let model = model_name(&model_args, &config);
+            if debug {
+                println!("Model: {model}");
             }
+            let context_length =
+                model_context_length(&client, &model, &context_args, &config, debug).await;
+            let mut prompt_builder = load_context(
+                String::new(),
+                &context_args,
+                &config,
+                context_length,
+                debug,
+                !debug && std::io::stderr().is_terminal(),
+            )
+            .await?;
+
+            let mut context: Option<Vec<i64>> = None;
+            let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+            loop {
+                print!("> ");
+                std::io::stdout().flush().unwrap();
+                let ctrl_c = cancel_on_ctrl_c();
+                let line = tokio::select! {
+                    line = lines.next_line() => line?,
+                    _ = ctrl_c.token().cancelled() => None,
+                };
+                drop(ctrl_c);
+                let Some(line) = line else {
+                    println!();
+                    break;
+                };
+                match line.trim() {
+                    "" => continue,
+                    "/exit" => break,
+                    "/reset" => {
+                        context = None;
+                        println!("Conversation context cleared");
+                        continue;
+                    }
+                    _ => {}
+                }
 
-            let client = ollama::LLMClient::default();
+                prompt_builder.set_prompt(line.clone());
+                let (prompt_with_context, prompt_stats) = prompt_builder.build()?;
+                let prompt = if context.is_none() {
+                    warn_context_overflow(&prompt_stats);
+                    prompt_with_context
+                } else {
+                    line
+                };
 
-            let mut stream =
-                GenerateRequest::new(&model.unwrap_or("llama3.2:latest".to_string()), &client)
-                    .system(include_str!("system.in"))
+                let ctrl_c = cancel_on_ctrl_c();
+                let mut stream = GenerateRequest::new(&model, &client)
+                    .system(&system)
+                    .options(model_parameters(&model_args, &config))
+                    .keep_alive(model_args.keep_alive.clone())
+                    .stop_sequences(model_args.stop_sequences.clone())
+                    .format(model_args.format.clone())
                     .num_ctx_options(prompt_stats.max_context)
+                    .images(if context.is_none() {
+                        prompt_builder.images().clone()
+                    } else {
+                        vec![]
+                    })
+                    .context(context.clone())
+                    .cancellation_token(ctrl_c.token().clone())
                     .prompt_stream(&prompt)
-                    .await?;
+                    .await
+                    .map_err(|error| request_error(error, &model, false))?;
 
-            while let Some(response) = stream.next().await {
-                if let Some(err) = response.error {
-                    return Err(anyhow!("LLM error: {err}"));
-                }
+                while let Some(response) = stream.next().await {
+                    if let Some(err) = response.error {
+                        eprintln!("LLM error: {err}");
+                        break;
+                    }
 
-                print!("{}", response.response);
-                std::io::stdout().flush().unwrap();
-                if response.done {
-                    println!();
+                    print!("{}", response.response);
+                    std::io::stdout().flush().unwrap();
+                    if response.done {
+                        println!();
+                        if response.context.is_some() {
+                            context = response.context;
+                        }
+                        if debug {
+                            println!("\n{:#^80}", " Debugging response ");
+                            println!("done_reason: {}", response.done_reason);
+                            println!("eval_count: {}", response.eval_count);
+                            println!("prompt_eval_count: {}", response.prompt_eval_count);
+                            println!("{:#^80}", "");
+                        }
+                    }
+                }
+                drop(ctrl_c);
+            }
+        }
+        Command::Show { name, model_args } => {
+            single_model(&model_args, "show")?;
+            let client = build_client(&model_args, &config)?;
+            let model = name.unwrap_or_else(|| model_name(&model_args, &config));
+            let shown = client
+                .show_model(&model)
+                .await
+                .map_err(|error| request_error(error, &model, false))?;
 
-                    if show_stats {
-                        println!("\n{:#^80}", " Reponse stats ");
-                        println!("model: {}", response.model);
-                        println!("eval_count: {}", response.eval_count);
-                        println!("prompt_eval_count: {}", response.prompt_eval_count);
-                        println!("error: {:?}", response.error);
-                        println!(
-                            "total_duration: {:?}",
-                            Duration::from_nanos(response.total_duration)
+            println!("{:#^80}", format!(" {model} "));
+            let details = [
+                ("Architecture", shown.architecture().map(str::to_string)),
+                (
+                    "Parameters",
+                    shown
+                        .parameter_count()
+                        .map(|count| format!("{count} ({})", shown.details.parameter_size)),
+                ),
+                (
+                    "Context length",
+                    shown.context_length().map(|length| length.to_string()),
+                ),
+                (
+                    "Quantization",
+                    Some(shown.details.quantization_level.clone()),
+                ),
+                ("Format", Some(shown.details.format.clone())),
+                ("Capabilities", Some(shown.capabilities.join(", "))),
+            ];
+            for (label, value) in details {
+                if let Some(value) = value.filter(|value| !value.is_empty()) {
+                    println!("{label:<16}{value}");
+                }
+            }
+            for (title, text) in [
+                (" Parameters ", &shown.parameters),
+                (" Template ", &shown.template),
+                (" Modelfile ", &shown.modelfile),
+            ] {
+                if !text.trim().is_empty() {
+                    println!("\n{title:#^80}");
+                    println!("{}", text.trim_end());
+                }
+            }
+        }
+        Command::Pull { name, model_args } => {
+            single_model(&model_args, "pull")?;
+            let client = build_client(&model_args, &config)?;
+            let model = name.unwrap_or_else(|| model_name(&model_args, &config));
+            let mut stream = client.pull_stream(&model).await.map_err(|error| {
+                if error.is_model_not_found() {
+                    anyhow!("Model {model:?} was not found in the registry")
+                } else {
+                    error.into()
+                }
+            })?;
+            let is_terminal = std::io::stderr().is_terminal();
+            let mut last_status = String::new();
+            while let Some(progress) = stream.next().await {
+                if let Some(error) = progress.error {
+                    if is_terminal {
+                        eprintln!();
+                    }
+                    return Err(anyhow!("Could not pull {model:?}: {error}"));
+                }
+                match (progress.completed, progress.total) {
+                    (Some(completed), Some(total)) if is_terminal => {
+                        eprint!(
+                            "\r\x1b[K{} {}",
+                            progress.status,
+                            progress_bar(completed, total)
                         );
-                        println!("{:#^80}", "");
+                        last_status = progress.status;
                     }
-
-                    if debug {
-                        println!("\n{:#^80}", " Debugging response ");
-                        println!("{:#?}", response);
-                        println!("{:#^80}", "");
+                    _ if progress.status != last_status => {
+                        if is_terminal {
+                            eprint!("\r\x1b[K");
+                        }
+                        eprintln!("{}", progress.status);
+                        last_status = progress.status;
                     }
+                    _ => {}
                 }
             }
         }
+        Command::Count {
+            prompt,
+            context_args,
+            strict,
+            output_format,
+        } => {
+            let prompt_builder = load_context(
+                prompt.unwrap_or_default(),
+                &context_args,
+                &config,
+                None,
+                false,
+                std::io::stderr().is_terminal(),
+            )
+            .await?;
+            let (_, prompt_stats) = prompt_builder.build()?;
+            let fits = prompt_stats.skipped_files.is_empty()
+                && prompt_stats.prompt_context_size_estimated <= prompt_stats.max_context;
+
+            if output_format == OutputFormat::Json {
+                println!(
+                    "{}",
+                    serde_json::json!({ "prompt_stats": prompt_stats, "fits": fits })
+                );
+            } else {
+                println!(
+                    "files: {} ({} skipped, {} truncated)",
+                    prompt_stats.file_count,
+                    prompt_stats.skipped_files.len(),
+                    prompt_stats.truncated_files.len()
+                );
+                println!("documents: {}", prompt_stats.document_count);
+                println!(
+                    "estimated tokens: ~{}",
+                    prompt_stats.prompt_context_size_estimated
+                );
+                println!("budget (num_ctx): {}", prompt_stats.max_context);
+                println!("fits: {}", if fits { "yes" } else { "no" });
+            }
+
+            if strict && !fits {
+                return Err(anyhow!(
+                    "The context (~{} tokens, {} files skipped) doesn't fit the budget of {}",
+                    prompt_stats.prompt_context_size_estimated,
+                    prompt_stats.skipped_files.len(),
+                    prompt_stats.max_context
+                ));
+            }
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Command::command(),
+                env!("CARGO_BIN_NAME"),
+                &mut std::io::stdout(),
+            );
+        }
     }
 
     Ok(())
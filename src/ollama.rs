@@ -1,8 +1,72 @@
-use anyhow::anyhow;
+use crate::fs::image_mime_type;
+use base64::prelude::*;
 use futures::stream::{Stream, StreamExt};
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
 
 const DEFAULT_API_URL: &str = "http://localhost:11434/api/generate";
+const DEFAULT_OPENAI_API_URL: &str = "http://localhost:11434/v1/chat/completions";
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    #[default]
+    Ollama,
+    #[value(name = "openai")]
+    OpenAICompatible,
+}
+
+impl Backend {
+    pub fn default_api_url(&self) -> &'static str {
+        match self {
+            Backend::Ollama => DEFAULT_API_URL,
+            Backend::OpenAICompatible => DEFAULT_OPENAI_API_URL,
+        }
+    }
+
+    /// Strips trailing slashes and completes a host-only URL (`http://host:11434`)
+    /// with this backend's endpoint path; other URLs are kept as given.
+    pub fn normalize_api_url(&self, api_url: &str) -> String {
+        let api_url = api_url.trim().trim_end_matches('/');
+        match reqwest::Url::parse(api_url) {
+            Ok(url) if url.path() == "/" && url.query().is_none() => {
+                let default_url = reqwest::Url::parse(self.default_api_url())
+                    .expect("default API URLs are valid");
+                format!("{api_url}{}", default_url.path())
+            }
+            _ => api_url.to_string(),
+        }
+    }
+}
+
+/// Follows 307/308 redirects, which resend the POST body, but fails on the others
+/// since they turn the request into a body-less GET.
+fn redirect_policy() -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if matches!(attempt.status().as_u16(), 307 | 308) {
+            attempt.follow()
+        } else {
+            let message = format!(
+                "redirected ({}) to {}, which would drop the request body; use that URL as the API URL",
+                attempt.status(),
+                attempt.url()
+            );
+            attempt.error(message)
+        }
+    })
+}
 
 #[derive(Debug, Serialize, Default)]
 pub struct GeneratePayload {
@@ -15,11 +79,61 @@ pub struct GeneratePayload {
     pub stream: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub options: Option<ModelParameters>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<KeepAlive>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<Vec<i64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum KeepAlive {
+    Seconds(i64),
+    Duration(String),
+}
+
+impl FromStr for KeepAlive {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(value
+            .parse::<i64>()
+            .map(KeepAlive::Seconds)
+            .unwrap_or_else(|_| KeepAlive::Duration(value.to_string())))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct ModelParameters {
     pub num_ctx: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_penalty: Option<f32>,
+    /// How far back `repeat_penalty` looks; 0 disables it, -1 means `num_ctx`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_last_n: Option<i64>,
+    /// 0 disables Mirostat sampling, 1 and 2 pick its version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat_tau: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirostat_eta: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i64>,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -36,12 +150,307 @@ pub struct GenerateResponse {
     pub prompt_eval_duration: u64,
     pub response: String,
     pub total_duration: u64,
+    pub context: Option<Vec<i64>>,
     pub error: Option<String>,
 }
 
+/// Token counts and durations of a finished generation; durations are in
+/// nanoseconds, as reported by the API.
+#[derive(Debug, Default, Serialize)]
+pub struct ResponseStats {
+    pub model: String,
+    pub done_reason: String,
+    pub eval_count: u64,
+    pub prompt_eval_count: u64,
+    pub total_duration: u64,
+    pub load_duration: u64,
+    pub prompt_eval_duration: u64,
+    pub eval_duration: u64,
+    /// `None` when the API reported no eval duration
+    pub eval_tokens_per_second: Option<f64>,
+    /// `None` when the API reported no prompt eval duration
+    pub prompt_tokens_per_second: Option<f64>,
+}
+
+impl ResponseStats {
+    pub fn load_phase(&self) -> Duration {
+        Duration::from_nanos(self.load_duration)
+    }
+
+    /// Time spent reading the prompt, i.e. the time to first token once loaded.
+    pub fn prompt_phase(&self) -> Duration {
+        Duration::from_nanos(self.prompt_eval_duration)
+    }
+
+    pub fn generation_phase(&self) -> Duration {
+        Duration::from_nanos(self.eval_duration)
+    }
+
+    /// `eval_tokens_per_second` with two decimals, or `n/a`.
+    pub fn eval_rate(&self) -> String {
+        format_rate(self.eval_tokens_per_second)
+    }
+
+    /// `prompt_tokens_per_second` with two decimals, or `n/a`.
+    pub fn prompt_rate(&self) -> String {
+        format_rate(self.prompt_tokens_per_second)
+    }
+}
+
+fn tokens_per_second(count: u64, duration: u64) -> Option<f64> {
+    (duration > 0).then(|| count as f64 / Duration::from_nanos(duration).as_secs_f64())
+}
+
+fn format_rate(rate: Option<f64>) -> String {
+    rate.map_or("n/a".to_string(), |rate| format!("{rate:.2}"))
+}
+
+impl From<&GenerateResponse> for ResponseStats {
+    fn from(response: &GenerateResponse) -> Self {
+        Self {
+            model: response.model.clone(),
+            done_reason: response.done_reason.clone(),
+            eval_count: response.eval_count,
+            prompt_eval_count: response.prompt_eval_count,
+            total_duration: response.total_duration,
+            load_duration: response.load_duration,
+            prompt_eval_duration: response.prompt_eval_duration,
+            eval_duration: response.eval_duration,
+            eval_tokens_per_second: tokens_per_second(response.eval_count, response.eval_duration),
+            prompt_tokens_per_second: tokens_per_second(
+                response.prompt_eval_count,
+                response.prompt_eval_duration,
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatMessage {
+    role: String,
+    content: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatStreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletionPayload {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<ChatStreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+}
+
+fn image_data_url(image: &str) -> String {
+    let header = image
+        .get(..16)
+        .and_then(|prefix| BASE64_STANDARD.decode(prefix).ok())
+        .unwrap_or_default();
+    let mime_type = image_mime_type(&header).unwrap_or("image/png");
+    format!("data:{mime_type};base64,{image}")
+}
+
+impl From<GeneratePayload> for ChatCompletionPayload {
+    fn from(payload: GeneratePayload) -> Self {
+        let stream = payload.stream.unwrap_or(false);
+        let images = payload.images.unwrap_or_default();
+        let messages = [("system", payload.system), ("user", payload.prompt)]
+            .into_iter()
+            .filter_map(|(role, content)| {
+                content.map(|content| ChatMessage {
+                    role: role.to_string(),
+                    content: if role == "user" && !images.is_empty() {
+                        serde_json::Value::Array(
+                            std::iter::once(serde_json::json!({ "type": "text", "text": content }))
+                                .chain(images.iter().map(|image| {
+                                    serde_json::json!({
+                                        "type": "image_url",
+                                        "image_url": { "url": image_data_url(image) },
+                                    })
+                                }))
+                                .collect(),
+                        )
+                    } else {
+                        content.into()
+                    },
+                })
+            })
+            .collect();
+        let options = payload.options.unwrap_or_default();
+
+        Self {
+            model: payload.model,
+            messages,
+            stream,
+            stream_options: stream.then_some(ChatStreamOptions {
+                include_usage: true,
+            }),
+            temperature: options.temperature,
+            top_p: options.top_p,
+            seed: options.seed,
+            max_tokens: options.num_predict.filter(|num_predict| *num_predict > 0),
+            response_format: payload.format.map(|format| match format {
+                serde_json::Value::String(_) => serde_json::json!({ "type": "json_object" }),
+                schema => serde_json::json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": "response", "schema": schema },
+                }),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ChatCompletionMessage {
+    content: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ChatCompletionChoice {
+    message: Option<ChatCompletionMessage>,
+    delta: Option<ChatCompletionMessage>,
+    finish_reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ChatCompletionUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ChatCompletionResponse {
+    model: String,
+    created: u64,
+    choices: Vec<ChatCompletionChoice>,
+    usage: Option<ChatCompletionUsage>,
+    error: Option<serde_json::Value>,
+}
+
+impl From<ChatCompletionResponse> for GenerateResponse {
+    fn from(chat_response: ChatCompletionResponse) -> Self {
+        let choice = chat_response.choices.into_iter().next().unwrap_or_default();
+        let usage = chat_response.usage.unwrap_or_default();
+        let finish_reason = choice.finish_reason;
+
+        GenerateResponse {
+            created_at: chat_response.created.to_string(),
+            done: finish_reason.is_some(),
+            done_reason: finish_reason.unwrap_or_default(),
+            eval_count: usage.completion_tokens,
+            model: chat_response.model,
+            prompt_eval_count: usage.prompt_tokens,
+            response: choice
+                .message
+                .or(choice.delta)
+                .and_then(|message| message.content)
+                .unwrap_or_default(),
+            error: chat_response.error.map(|error| error.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct EmbedPayload<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShowPayload<'a> {
+    model: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct PullPayload<'a> {
+    model: &'a str,
+    stream: bool,
+}
+
+/// One progress update of `/api/pull`; `total`/`completed` are bytes of the layer
+/// named by `digest`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PullProgress {
+    pub status: String,
+    pub digest: Option<String>,
+    pub total: Option<u64>,
+    pub completed: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// What `/api/show` reports about an installed model.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelShow {
+    pub modelfile: String,
+    pub parameters: String,
+    pub template: String,
+    pub details: ModelDetails,
+    pub model_info: serde_json::Map<String, serde_json::Value>,
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelDetails {
+    pub format: String,
+    pub family: String,
+    pub parameter_size: String,
+    pub quantization_level: String,
+}
+
+impl ModelShow {
+    pub fn architecture(&self) -> Option<&str> {
+        self.model_info
+            .get("general.architecture")
+            .and_then(|value| value.as_str())
+    }
+
+    pub fn context_length(&self) -> Option<u64> {
+        self.model_info
+            .iter()
+            .find(|(key, _)| key.ends_with(".context_length"))
+            .and_then(|(_, value)| value.as_u64())
+    }
+
+    pub fn parameter_count(&self) -> Option<u64> {
+        self.model_info
+            .get("general.parameter_count")
+            .and_then(|value| value.as_u64())
+    }
+}
+
 pub struct GenerateRequest<'a> {
     client: &'a LLMClient,
     payload: GeneratePayload,
+    cancellation_token: Option<CancellationToken>,
+    stop_sequences: Vec<String>,
 }
 
 impl<'a> GenerateRequest<'a> {
@@ -52,14 +461,65 @@ impl<'a> GenerateRequest<'a> {
                 model: model.to_string(),
                 ..Default::default()
             },
+            cancellation_token: None,
+            stop_sequences: vec![],
         }
     }
 
+    pub fn stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = stop_sequences
+            .into_iter()
+            .filter(|stop_sequence| !stop_sequence.is_empty())
+            .collect();
+        self
+    }
+
+    pub fn cancellation_token(mut self, cancellation_token: CancellationToken) -> Self {
+        self.cancellation_token = Some(cancellation_token);
+        self
+    }
+
     pub fn system(mut self, system: &str) -> Self {
         self.payload.system = Some(system.to_string());
         self
     }
 
+    pub fn options(mut self, options: ModelParameters) -> Self {
+        let num_ctx = self.payload.options.and_then(|options| options.num_ctx);
+        self.payload.options = Some(ModelParameters {
+            num_ctx: options.num_ctx.or(num_ctx),
+            ..options
+        });
+        self
+    }
+
+    pub fn keep_alive(mut self, keep_alive: Option<KeepAlive>) -> Self {
+        self.payload.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn format(mut self, format: Option<serde_json::Value>) -> Self {
+        self.payload.format = format;
+        self
+    }
+
+    pub fn images(mut self, images: Vec<String>) -> Self {
+        self.payload.images = (!images.is_empty()).then_some(images);
+        self
+    }
+
+    /// Sends the prompt verbatim, without the model's prompt template; combine with
+    /// no `system`, which the template would otherwise place.
+    pub fn raw(mut self, raw: bool) -> Self {
+        self.payload.raw = raw.then_some(true);
+        self
+    }
+
+    pub fn context(mut self, context: Option<Vec<i64>>) -> Self {
+        self.payload.context = context;
+        self
+    }
+
     pub fn num_ctx_options(mut self, num_ctx: u64) -> Self {
         let mut options = self.payload.options.unwrap_or_default();
         options.num_ctx = Some(num_ctx);
@@ -70,22 +530,328 @@ impl<'a> GenerateRequest<'a> {
     pub async fn prompt_stream(
         mut self,
         prompt: &str,
-    ) -> anyhow::Result<impl Stream<Item = GenerateResponse>> {
+    ) -> Result<impl Stream<Item = GenerateResponse> + use<>, LLMError> {
         self.payload.prompt = Some(prompt.to_string());
         self.payload.stream = Some(true);
-        self.client.generate_stream(self.payload).await
+        if self.stop_sequences.is_empty() {
+            return Ok(self
+                .client
+                .generate_stream(self.payload, self.cancellation_token)
+                .await?
+                .left_stream());
+        }
+
+        let cancellation_token = self
+            .cancellation_token
+            .map(|cancellation_token| cancellation_token.child_token())
+            .unwrap_or_default();
+        let stream = self
+            .client
+            .generate_stream(self.payload, Some(cancellation_token.clone()))
+            .await?;
+        Ok(stop_at_sequences(stream, self.stop_sequences, cancellation_token).right_stream())
     }
 
-    pub async fn prompt(mut self, prompt: &str) -> anyhow::Result<GenerateResponse> {
+    pub async fn prompt(mut self, prompt: &str) -> Result<GenerateResponse, LLMError> {
         self.payload.prompt = Some(prompt.to_string());
         self.payload.stream = Some(false);
-        self.client.generate(self.payload).await
+        let mut response = self.client.generate(self.payload).await?;
+        if let Some(position) = find_stop_sequence(&response.response, &self.stop_sequences) {
+            response.response.truncate(position);
+            response.done_reason = "stop".to_string();
+        }
+        Ok(response)
+    }
+}
+
+fn find_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter_map(|stop_sequence| text.find(stop_sequence.as_str()))
+        .min()
+}
+
+fn partial_stop_sequence_len(text: &str, stop_sequences: &[String]) -> usize {
+    stop_sequences
+        .iter()
+        .filter_map(|stop_sequence| {
+            (1..stop_sequence.len()).rev().find(|&len| {
+                stop_sequence.is_char_boundary(len) && text.ends_with(&stop_sequence[..len])
+            })
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn stop_at_sequences(
+    stream: impl Stream<Item = GenerateResponse> + Unpin + Send + 'static,
+    stop_sequences: Vec<String>,
+    cancellation_token: CancellationToken,
+) -> impl Stream<Item = GenerateResponse> + Unpin {
+    futures::stream::unfold(Some((stream, String::new())), move |state| {
+        let stop_sequences = stop_sequences.clone();
+        let cancellation_token = cancellation_token.clone();
+        async move {
+            let (mut stream, mut pending) = state?;
+            let Some(mut response) = stream.next().await else {
+                let response = GenerateResponse {
+                    response: pending,
+                    ..Default::default()
+                };
+                return (!response.response.is_empty()).then_some((response, None));
+            };
+
+            pending.push_str(&response.response);
+            if let Some(position) = find_stop_sequence(&pending, &stop_sequences) {
+                cancellation_token.cancel();
+                pending.truncate(position);
+                response.response = pending;
+                response.done = true;
+                response.done_reason = "stop".to_string();
+                return Some((response, None));
+            }
+            if response.done {
+                response.response = pending;
+                return Some((response, None));
+            }
+
+            let emitted_len = pending.len() - partial_stop_sequence_len(&pending, &stop_sequences);
+            response.response = pending[..emitted_len].to_string();
+            pending.drain(..emitted_len);
+            Some((response, Some((stream, pending))))
+        }
+    })
+    .boxed()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LLMError {
+    #[error("Could not connect after {attempts} attempt(s)")]
+    Connection {
+        attempts: usize,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("API returned HTTP status {0}")]
+    HttpStatus(u16),
+    #[error("API error: {0}")]
+    ApiError(serde_json::Value),
+    #[error("Invalid response: {0}")]
+    Decode(String),
+    #[error("Request to {} timed out after {timeout:?}", url.as_ref().map_or("API".to_string(), |url| url.to_string()))]
+    Timeout {
+        url: Option<reqwest::Url>,
+        timeout: Duration,
+    },
+    #[error("Invalid API URL {0}")]
+    InvalidUrl(String),
+    #[error("Invalid header {0}")]
+    InvalidHeader(String),
+    #[error("Invalid proxy URL {0}")]
+    InvalidProxy(String),
+    #[error("Invalid CA certificate {0}")]
+    InvalidCertificate(String),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+impl LLMError {
+    /// Whether the API rejected the request because the model isn't installed, i.e.
+    /// Ollama answered `{"error":"model \"...\" not found, ..."}`; a bare 404 can just
+    /// as well be a wrong API URL.
+    pub fn is_model_not_found(&self) -> bool {
+        match self {
+            LLMError::ApiError(error) => error
+                .get("error")
+                .and_then(|error| error.as_str())
+                .is_some_and(|error| error.starts_with("model ") && error.contains("not found")),
+            _ => false,
+        }
+    }
+
+    fn from_reqwest(error: reqwest::Error, timeout: Option<Duration>, attempts: usize) -> Self {
+        match timeout {
+            Some(timeout) if error.is_timeout() => LLMError::Timeout {
+                url: error.url().cloned(),
+                timeout,
+            },
+            _ if error.is_connect() => LLMError::Connection {
+                attempts,
+                source: error,
+            },
+            _ if error.is_decode() => LLMError::Decode(error.to_string()),
+            _ => LLMError::Request(error),
+        }
+    }
+
+    async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status().as_u16();
+        match response.json::<serde_json::Value>().await {
+            Ok(error_response) => LLMError::ApiError(error_response),
+            Err(_) => LLMError::HttpStatus(status),
+        }
     }
 }
 
+fn next_line(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let position = buffer.iter().position(|byte| *byte == b'\n')?;
+    Some(buffer.drain(..=position).collect())
+}
+
+pub struct LLMClientBuilder {
+    api_url: String,
+    backend: Backend,
+    timeout: Option<Duration>,
+    retries: usize,
+    headers: Vec<(String, String)>,
+    bearer_token: Option<String>,
+    proxy: Option<String>,
+    no_proxy: bool,
+    ca_cert: Option<PathBuf>,
+    insecure: bool,
+}
+
+impl LLMClientBuilder {
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn headers<K: AsRef<str>, V: AsRef<str>>(
+        mut self,
+        headers: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        self.headers.extend(
+            headers
+                .into_iter()
+                .map(|(name, value)| (name.as_ref().to_string(), value.as_ref().to_string())),
+        );
+        self
+    }
+
+    pub fn bearer_token(mut self, token: Option<String>) -> Self {
+        self.bearer_token = token;
+        self
+    }
+
+    /// Sends every request through this proxy instead of the one from the
+    /// `HTTP_PROXY`/`HTTPS_PROXY` environment variables.
+    pub fn proxy(mut self, proxy: Option<String>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Connects directly, ignoring both `proxy` and the proxy environment variables.
+    pub fn no_proxy(mut self, flag: bool) -> Self {
+        self.no_proxy = flag;
+        self
+    }
+
+    /// Also trusts the certificates in this PEM (or DER) file, e.g. an internal CA.
+    pub fn ca_cert(mut self, path: Option<PathBuf>) -> Self {
+        self.ca_cert = path;
+        self
+    }
+
+    /// Accepts any TLS certificate, even invalid or self-signed ones; for testing only.
+    pub fn insecure(mut self, flag: bool) -> Self {
+        self.insecure = flag;
+        self
+    }
+
+    fn certificates(&self) -> Result<Vec<reqwest::Certificate>, LLMError> {
+        let Some(path) = &self.ca_cert else {
+            return Ok(vec![]);
+        };
+        let invalid = |error: &dyn std::fmt::Display| {
+            LLMError::InvalidCertificate(format!("{}: {error}", path.display()))
+        };
+        let content = std::fs::read(path).map_err(|error| invalid(&error))?;
+        match reqwest::Certificate::from_pem_bundle(&content) {
+            Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+            _ => reqwest::Certificate::from_der(&content)
+                .map(|certificate| vec![certificate])
+                .map_err(|error| invalid(&error)),
+        }
+    }
+
+    fn default_headers(&self) -> Result<HeaderMap, LLMError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|error| LLMError::InvalidHeader(format!("{name:?}: {error}")))?;
+            let mut header_value = HeaderValue::from_str(value)
+                .map_err(|error| LLMError::InvalidHeader(format!("{name:?}: {error}")))?;
+            header_value.set_sensitive(header_name == AUTHORIZATION);
+            headers.insert(header_name, header_value);
+        }
+        if let Some(token) = &self.bearer_token {
+            let mut header_value = HeaderValue::from_str(&format!("Bearer {token}"))
+                .map_err(|error| LLMError::InvalidHeader(format!("{AUTHORIZATION}: {error}")))?;
+            header_value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, header_value);
+        }
+        Ok(headers)
+    }
+
+    pub fn build(self) -> Result<LLMClient, LLMError> {
+        let mut client = reqwest::Client::builder()
+            .default_headers(self.default_headers()?)
+            .redirect(redirect_policy());
+        if let Some(timeout) = self.timeout {
+            client = client.connect_timeout(timeout).read_timeout(timeout);
+        }
+        for certificate in self.certificates()? {
+            client = client.add_root_certificate(certificate);
+        }
+        if self.insecure {
+            client = client.danger_accept_invalid_certs(true);
+        }
+        if self.no_proxy {
+            client = client.no_proxy();
+        } else if let Some(proxy) = &self.proxy {
+            client = client.proxy(
+                reqwest::Proxy::all(proxy)
+                    .map_err(|error| LLMError::InvalidProxy(format!("{proxy}: {error}")))?,
+            );
+        }
+
+        Ok(LLMClient {
+            api_url: self.backend.normalize_api_url(&self.api_url),
+            backend: self.backend,
+            timeout: self.timeout,
+            retries: self.retries,
+            client: client.build()?,
+            context_lengths: Arc::default(),
+        })
+    }
+}
+
+/// Holds one pooled HTTP client: build it once and share it, by reference or by
+/// cloning (clones share the pool), across sequential and concurrent requests.
+#[derive(Debug, Clone)]
 pub struct LLMClient {
     api_url: String,
+    backend: Backend,
+    timeout: Option<Duration>,
+    retries: usize,
     client: reqwest::Client,
+    context_lengths: Arc<Mutex<HashMap<String, Option<u64>>>>,
 }
 
 impl Default for LLMClient {
@@ -97,44 +863,213 @@ impl Default for LLMClient {
 impl LLMClient {
     pub fn new(api_url: &str) -> Self {
         Self {
+            api_url: Backend::default().normalize_api_url(api_url),
+            backend: Backend::default(),
+            timeout: None,
+            retries: 0,
+            client: reqwest::Client::builder()
+                .redirect(redirect_policy())
+                .build()
+                .expect("a client without TLS or proxy settings builds"),
+            context_lengths: Arc::default(),
+        }
+    }
+
+    pub fn builder(api_url: &str) -> LLMClientBuilder {
+        LLMClientBuilder {
             api_url: api_url.to_string(),
-            client: reqwest::Client::new(),
+            backend: Backend::default(),
+            timeout: None,
+            retries: 0,
+            headers: vec![],
+            bearer_token: None,
+            proxy: None,
+            no_proxy: false,
+            ca_cert: None,
+            insecure: false,
+        }
+    }
+
+    async fn send(&self, payload: GeneratePayload) -> Result<reqwest::Response, LLMError> {
+        let request = match self.backend {
+            Backend::Ollama => self.client.post(&self.api_url).json(&payload),
+            Backend::OpenAICompatible => self
+                .client
+                .post(&self.api_url)
+                .json(&ChatCompletionPayload::from(payload)),
+        };
+
+        self.execute(request).await
+    }
+
+    async fn execute(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, LLMError> {
+        let mut attempt = 0;
+        loop {
+            let Some(attempt_request) = request.try_clone() else {
+                return request
+                    .send()
+                    .await
+                    .map_err(|error| LLMError::from_reqwest(error, self.timeout, 1));
+            };
+
+            attempt += 1;
+            match attempt_request.send().await {
+                Ok(response) => return Ok(response),
+                Err(error) if error.is_connect() && attempt <= self.retries => {
+                    tokio::time::sleep(RETRY_BASE_DELAY * 2u32.pow(attempt as u32 - 1)).await;
+                }
+                Err(error) => return Err(LLMError::from_reqwest(error, self.timeout, attempt)),
+            }
         }
     }
 
     pub async fn generate_stream(
         &self,
         payload: GeneratePayload,
-    ) -> anyhow::Result<impl Stream<Item = GenerateResponse>> {
+        cancellation_token: Option<CancellationToken>,
+    ) -> Result<impl Stream<Item = GenerateResponse> + use<>, LLMError> {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let model = payload.model.clone();
+        let cancellation_token = cancellation_token.unwrap_or_default();
 
-        let response = self
-            .client
-            .post(&self.api_url)
-            .json(&payload)
-            .send()
-            .await?;
+        let response = self.send(payload).await?;
 
         if response.error_for_status_ref().is_err() {
-            let error_response: serde_json::Value = response.json().await?;
-            Err(anyhow!("API error: {error_response}"))
+            Err(LLMError::from_response(response).await)
         } else {
+            let timeout = self.timeout;
             let mut stream = response.bytes_stream();
             let mut no_parsed_chunks: Vec<u8> = vec![];
 
+            if self.backend == Backend::OpenAICompatible {
+                tokio::spawn(async move {
+                    let mut last_response = GenerateResponse {
+                        model,
+                        ..Default::default()
+                    };
+                    loop {
+                        let chunk = tokio::select! {
+                            _ = cancellation_token.cancelled() => {
+                                last_response.done = true;
+                                last_response.done_reason = "cancelled".to_string();
+                                let _ = tx.send(last_response);
+                                return;
+                            }
+                            chunk = stream.next() => chunk,
+                        };
+                        let Some(chunk) = chunk else {
+                            break;
+                        };
+                        let chunk = match chunk {
+                            Ok(chunk) => chunk,
+                            Err(error) => {
+                                let _ = tx.send(GenerateResponse {
+                                    error: Some(
+                                        LLMError::from_reqwest(error, timeout, 1).to_string(),
+                                    ),
+                                    ..Default::default()
+                                });
+                                return;
+                            }
+                        };
+                        no_parsed_chunks.extend_from_slice(&chunk);
+
+                        while let Some(line) = next_line(&mut no_parsed_chunks) {
+                            let Some(data) = line.trim_ascii().strip_prefix(b"data:") else {
+                                continue;
+                            };
+                            let data = data.trim_ascii();
+                            if data == b"[DONE]" {
+                                break;
+                            }
+
+                            let Ok(chat_response) =
+                                serde_json::from_slice::<ChatCompletionResponse>(data)
+                            else {
+                                continue;
+                            };
+                            let mut response = GenerateResponse::from(chat_response);
+                            if response.done {
+                                last_response.done_reason = response.done_reason;
+                                response.done = false;
+                                response.done_reason = String::new();
+                            }
+                            if response.eval_count > 0 || response.prompt_eval_count > 0 {
+                                last_response.eval_count = response.eval_count;
+                                last_response.prompt_eval_count = response.prompt_eval_count;
+                            }
+                            last_response.model = response.model.clone();
+                            last_response.created_at = response.created_at.clone();
+
+                            if (!response.response.is_empty() || response.error.is_some())
+                                && tx.send(response).is_err()
+                            {
+                                return;
+                            }
+                        }
+                    }
+
+                    last_response.done = true;
+                    let _ = tx.send(last_response);
+                });
+
+                return Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(rx));
+            }
+
             tokio::spawn(async move {
-                while let Some(chunk) = stream.next().await {
-                    let chunk = chunk.unwrap();
-                    if let Ok(chunk) = serde_json::from_slice::<GenerateResponse>(&chunk) {
-                        tx.send(chunk).unwrap();
-                    } else {
-                        no_parsed_chunks = [no_parsed_chunks, chunk.to_vec()].concat();
+                loop {
+                    let chunk = tokio::select! {
+                        _ = cancellation_token.cancelled() => {
+                            let _ = tx.send(GenerateResponse {
+                                model,
+                                done: true,
+                                done_reason: "cancelled".to_string(),
+                                ..Default::default()
+                            });
+                            return;
+                        }
+                        chunk = stream.next() => chunk,
+                    };
+                    let Some(chunk) = chunk else {
+                        break;
+                    };
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(error) => {
+                            let _ = tx.send(GenerateResponse {
+                                error: Some(LLMError::from_reqwest(error, timeout, 1).to_string()),
+                                ..Default::default()
+                            });
+                            return;
+                        }
+                    };
+                    no_parsed_chunks.extend_from_slice(&chunk);
+
+                    while let Some(line) = next_line(&mut no_parsed_chunks) {
+                        if line.trim_ascii().is_empty() {
+                            continue;
+                        }
+                        let response = serde_json::from_slice::<GenerateResponse>(&line)
+                            .unwrap_or_else(|error| GenerateResponse {
+                                error: Some(LLMError::Decode(error.to_string()).to_string()),
+                                ..Default::default()
+                            });
+                        if tx.send(response).is_err() {
+                            return;
+                        }
                     }
                 }
-                if !no_parsed_chunks.is_empty() {
-                    let chunk =
-                        serde_json::from_slice::<GenerateResponse>(&no_parsed_chunks).unwrap();
-                    tx.send(chunk).unwrap();
+
+                if !no_parsed_chunks.trim_ascii().is_empty() {
+                    let response = serde_json::from_slice::<GenerateResponse>(&no_parsed_chunks)
+                        .unwrap_or_else(|error| GenerateResponse {
+                            error: Some(LLMError::Decode(error.to_string()).to_string()),
+                            ..Default::default()
+                        });
+                    let _ = tx.send(response);
                 }
             });
 
@@ -142,20 +1077,439 @@ impl LLMClient {
         }
     }
 
-    pub async fn generate(&self, payload: GeneratePayload) -> anyhow::Result<GenerateResponse> {
-        let response = self
-            .client
-            .post(&self.api_url)
-            .json(&payload)
-            .send()
-            .await?;
+    pub async fn generate(&self, payload: GeneratePayload) -> Result<GenerateResponse, LLMError> {
+        let response = self.send(payload).await?;
 
         if response.error_for_status_ref().is_err() {
-            let error_response: serde_json::Value = response.json().await?;
-            return Err(anyhow!("API error: {error_response}"));
+            return Err(LLMError::from_response(response).await);
         }
 
-        let generated: GenerateResponse = response.json().await?;
+        let generated = match self.backend {
+            Backend::Ollama => response
+                .json::<GenerateResponse>()
+                .await
+                .map_err(|error| LLMError::from_reqwest(error, self.timeout, 1))?,
+            Backend::OpenAICompatible => response
+                .json::<ChatCompletionResponse>()
+                .await
+                .map_err(|error| LLMError::from_reqwest(error, self.timeout, 1))?
+                .into(),
+        };
         Ok(generated)
     }
+
+    pub async fn embeddings(
+        &self,
+        model: &str,
+        input: &[String],
+    ) -> Result<Vec<Vec<f32>>, LLMError> {
+        let request = self
+            .client
+            .post(self.endpoint("/api/embed")?)
+            .json(&EmbedPayload { model, input });
+        let response = self.execute(request).await?;
+
+        if response.error_for_status_ref().is_err() {
+            return Err(LLMError::from_response(response).await);
+        }
+
+        let embedded = response
+            .json::<EmbedResponse>()
+            .await
+            .map_err(|error| LLMError::from_reqwest(error, self.timeout, 1))?;
+        Ok(embedded.embeddings)
+    }
+
+    /// Fetches the model's details from `/api/show`; fails with an error for which
+    /// [`LLMError::is_model_not_found`] holds when the model isn't pulled.
+    pub async fn show_model(&self, model: &str) -> Result<ModelShow, LLMError> {
+        let request = self
+            .client
+            .post(self.endpoint("/api/show")?)
+            .json(&ShowPayload { model });
+        let response = self.execute(request).await?;
+
+        if response.error_for_status_ref().is_err() {
+            return Err(LLMError::from_response(response).await);
+        }
+
+        response
+            .json::<ModelShow>()
+            .await
+            .map_err(|error| LLMError::from_reqwest(error, self.timeout, 1))
+    }
+
+    /// Downloads the model through `/api/pull`, streaming its progress; the last
+    /// update has the status `success`, and failures arrive as updates with `error`.
+    pub async fn pull_stream(
+        &self,
+        model: &str,
+    ) -> Result<impl Stream<Item = PullProgress> + use<>, LLMError> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let request = self
+            .client
+            .post(self.endpoint("/api/pull")?)
+            .json(&PullPayload {
+                model,
+                stream: true,
+            });
+        let response = self.execute(request).await?;
+
+        if response.error_for_status_ref().is_err() {
+            return Err(LLMError::from_response(response).await);
+        }
+
+        let timeout = self.timeout;
+        let mut stream = response.bytes_stream();
+        let mut no_parsed_chunks: Vec<u8> = vec![];
+        let parse = |line: &[u8]| {
+            serde_json::from_slice::<PullProgress>(line).unwrap_or_else(|error| PullProgress {
+                error: Some(LLMError::Decode(error.to_string()).to_string()),
+                ..Default::default()
+            })
+        };
+        tokio::spawn(async move {
+            while let Some(chunk) = stream.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(error) => {
+                        let _ = tx.send(PullProgress {
+                            error: Some(LLMError::from_reqwest(error, timeout, 1).to_string()),
+                            ..Default::default()
+                        });
+                        return;
+                    }
+                };
+                no_parsed_chunks.extend_from_slice(&chunk);
+
+                while let Some(line) = next_line(&mut no_parsed_chunks) {
+                    if line.trim_ascii().is_empty() {
+                        continue;
+                    }
+                    if tx.send(parse(&line)).is_err() {
+                        return;
+                    }
+                }
+            }
+
+            if !no_parsed_chunks.trim_ascii().is_empty() {
+                let _ = tx.send(parse(&no_parsed_chunks));
+            }
+        });
+
+        Ok(tokio_stream::wrappers::UnboundedReceiverStream::new(rx))
+    }
+
+    /// The model's context window from `/api/show`, or `None` when the model doesn't
+    /// report one; answers are cached per model and shared by clones of the client.
+    /// Always `None` with the OpenAI-compatible backend, which has no such endpoint.
+    pub async fn context_length(&self, model: &str) -> Result<Option<u64>, LLMError> {
+        if self.backend != Backend::Ollama {
+            return Ok(None);
+        }
+        if let Some(context_length) = self.context_lengths.lock().unwrap().get(model) {
+            return Ok(*context_length);
+        }
+
+        let context_length = self.show_model(model).await?.context_length();
+        self.context_lengths
+            .lock()
+            .unwrap()
+            .insert(model.to_string(), context_length);
+        Ok(context_length)
+    }
+
+    fn endpoint(&self, path: &str) -> Result<reqwest::Url, LLMError> {
+        reqwest::Url::parse(&self.api_url)
+            .and_then(|url| url.join(path))
+            .map_err(|error| LLMError::InvalidUrl(format!("{}: {error}", self.api_url)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Serves every request with the chunks `respond` returns for its JSON body,
+    /// each sent as its own HTTP chunk; returns the API URL.
+    async fn serve<F>(respond: F) -> String
+    where
+        F: Fn(serde_json::Value) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        serve_with_headers("", respond).await
+    }
+
+    /// Like [`serve`], with `headers` (`Name: value\r\n` lines) added to every response.
+    async fn serve_with_headers<F>(headers: &'static str, respond: F) -> String
+    where
+        F: Fn(serde_json::Value) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}/api/generate", listener.local_addr().unwrap());
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(socket, headers, respond.clone()));
+            }
+        });
+        api_url
+    }
+
+    async fn handle_connection<F>(socket: TcpStream, headers: &str, respond: Arc<F>)
+    where
+        F: Fn(serde_json::Value) -> Vec<Vec<u8>>,
+    {
+        let mut socket = BufReader::new(socket);
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if socket.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                if line.trim_end().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("content-length")
+                {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            socket.read_exact(&mut body).await.unwrap();
+
+            let chunks = respond(serde_json::from_slice(&body).unwrap());
+            let socket = socket.get_mut();
+            socket
+                .write_all(
+                    format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n{headers}\r\n")
+                        .as_bytes(),
+                )
+                .await
+                .unwrap();
+            for chunk in chunks {
+                socket
+                    .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+                    .await
+                    .unwrap();
+                socket.write_all(&chunk).await.unwrap();
+                socket.write_all(b"\r\n").await.unwrap();
+                socket.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            socket.write_all(b"0\r\n\r\n").await.unwrap();
+        }
+    }
+
+    #[test]
+    fn normalize_api_url_completes_host_only_urls_and_strips_slashes() {
+        let ollama = Backend::Ollama;
+        assert_eq!(
+            ollama.normalize_api_url("http://gpu-box:11434"),
+            "http://gpu-box:11434/api/generate"
+        );
+        assert_eq!(
+            ollama.normalize_api_url("http://gpu-box:11434/"),
+            "http://gpu-box:11434/api/generate"
+        );
+        assert_eq!(
+            ollama.normalize_api_url("http://gpu-box:11434/api/generate/"),
+            "http://gpu-box:11434/api/generate"
+        );
+        assert_eq!(
+            ollama.normalize_api_url("https://example.com/ollama/api/generate"),
+            "https://example.com/ollama/api/generate"
+        );
+        assert_eq!(
+            Backend::OpenAICompatible.normalize_api_url("http://gpu-box:8080/"),
+            "http://gpu-box:8080/v1/chat/completions"
+        );
+    }
+
+    fn split_every(bytes: &[u8], size: usize) -> Vec<Vec<u8>> {
+        bytes.chunks(size).map(<[u8]>::to_vec).collect()
+    }
+
+    #[tokio::test]
+    async fn stream_parses_ndjson_lines_split_across_chunks() {
+        let body = concat!(
+            r#"{"model":"m","response":"Hel","done":false}"#,
+            "\n",
+            r#"{"model":"m","response":"lo wörld","done":false}"#,
+            "\n",
+            r#"{"model":"m","response":"","done":true,"done_reason":"stop"}"#,
+            "\n",
+        );
+        // 7-byte chunks cut lines, and the `ö`, at arbitrary points.
+        let api_url = serve(move |_| split_every(body.as_bytes(), 7)).await;
+        let client = LLMClient::new(&api_url);
+
+        let responses: Vec<_> = GenerateRequest::new("m", &client)
+            .prompt_stream("hi")
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let texts: Vec<_> = responses
+            .iter()
+            .map(|response| &response.response[..])
+            .collect();
+        assert_eq!(texts, ["Hel", "lo wörld", ""]);
+        assert!(responses.iter().all(|response| response.error.is_none()));
+        assert!(responses[2].done);
+        assert_eq!(responses[2].done_reason, "stop");
+    }
+
+    #[tokio::test]
+    async fn stream_parses_gzip_compressed_ndjson() {
+        use std::io::Write;
+
+        let lines: String = (0..50)
+            .map(|index| format!("{{\"model\":\"m\",\"response\":\"{index} \",\"done\":false}}\n"))
+            .chain([r#"{"model":"m","response":"","done":true,"done_reason":"stop"}"#.to_string()])
+            .collect();
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(lines.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let api_url = serve_with_headers("Content-Encoding: gzip\r\n", move |_| {
+            split_every(&compressed, 16)
+        })
+        .await;
+        let client = LLMClient::new(&api_url);
+
+        let responses: Vec<_> = GenerateRequest::new("m", &client)
+            .prompt_stream("hi")
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(responses.len(), 51);
+        assert!(responses.iter().all(|response| response.error.is_none()));
+        let text: String = responses
+            .iter()
+            .map(|response| &response.response[..])
+            .collect();
+        assert_eq!(
+            text,
+            (0..50).map(|index| format!("{index} ")).collect::<String>()
+        );
+        assert!(responses[50].done);
+    }
+
+    #[tokio::test]
+    async fn second_request_carries_the_first_response_context() {
+        let (payloads, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let api_url = serve(move |payload| {
+            let context = match payload.get("context") {
+                Some(_) => serde_json::json!([1, 2, 3, 4, 5]),
+                None => serde_json::json!([1, 2, 3]),
+            };
+            payloads.send(payload).unwrap();
+            let response = serde_json::json!({
+                "model": "m",
+                "response": "ok",
+                "done": true,
+                "context": context,
+            });
+            vec![response.to_string().into_bytes()]
+        })
+        .await;
+        let client = LLMClient::new(&api_url);
+
+        let first = GenerateRequest::new("m", &client)
+            .prompt("first")
+            .await
+            .unwrap();
+        assert_eq!(first.context, Some(vec![1, 2, 3]));
+        let second = GenerateRequest::new("m", &client)
+            .context(first.context)
+            .prompt("second")
+            .await
+            .unwrap();
+        assert_eq!(second.context, Some(vec![1, 2, 3, 4, 5]));
+
+        let first_payload = received.recv().await.unwrap();
+        assert_eq!(first_payload["prompt"], "first");
+        assert!(first_payload.get("context").is_none());
+        let second_payload = received.recv().await.unwrap();
+        assert_eq!(second_payload["prompt"], "second");
+        assert_eq!(second_payload["context"], serde_json::json!([1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn one_client_serves_concurrent_requests() {
+        let api_url = serve(|payload| {
+            let response = serde_json::json!({
+                "model": payload["model"],
+                "response": format!("echo: {}", payload["prompt"].as_str().unwrap()),
+                "done": true,
+            });
+            vec![response.to_string().into_bytes()]
+        })
+        .await;
+        let client = LLMClient::new(&api_url);
+
+        let prompts: Vec<_> = (0..8).map(|index| format!("prompt {index}")).collect();
+        let responses = futures::future::join_all(
+            prompts
+                .iter()
+                .map(|prompt| GenerateRequest::new("m", &client).prompt(prompt)),
+        )
+        .await;
+        for (prompt, response) in prompts.iter().zip(responses) {
+            assert_eq!(response.unwrap().response, format!("echo: {prompt}"));
+        }
+    }
+
+    /// Streams one NDJSON line per HTTP chunk for each of `pieces`, then a final
+    /// `done` line whose `length` reason tells it apart from a stop sequence match.
+    fn piece_lines(pieces: &'static [&'static str]) -> Vec<Vec<u8>> {
+        pieces
+            .iter()
+            .map(|piece| {
+                format!("{}\n", serde_json::json!({"model": "m", "response": piece, "done": false}))
+            })
+            .chain([format!(
+                "{}\n",
+                serde_json::json!({"model": "m", "response": "", "done": true, "done_reason": "length"})
+            )])
+            .map(String::into_bytes)
+            .collect()
+    }
+
+    async fn stop_sequence_stream(pieces: &'static [&'static str]) -> (String, GenerateResponse) {
+        let api_url = serve(move |_| piece_lines(pieces)).await;
+        let client = LLMClient::new(&api_url);
+        let mut responses: Vec<_> = GenerateRequest::new("m", &client)
+            .stop_sequences(vec!["END".to_string()])
+            .prompt_stream("hi")
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let text = responses
+            .iter()
+            .map(|response| &response.response[..])
+            .collect();
+        (text, responses.pop().unwrap())
+    }
+
+    #[tokio::test]
+    async fn stop_sequence_split_across_chunks_ends_the_stream() {
+        let (text, last) = stop_sequence_stream(&["foo EN", "D bar"]).await;
+        assert_eq!(text, "foo ");
+        assert!(last.done);
+        assert_eq!(last.done_reason, "stop");
+    }
+
+    #[tokio::test]
+    async fn partial_stop_sequence_is_flushed_when_it_does_not_complete() {
+        let (text, last) = stop_sequence_stream(&["foo E", "xit now, EN"]).await;
+        assert_eq!(text, "foo Exit now, EN");
+        assert!(last.done);
+        assert_eq!(last.done_reason, "length");
+    }
 }
@@ -1,27 +1,408 @@
+use crate::comments;
+use crate::extract;
+use crate::fs::{FileSearcher, image_mime_type, looks_binary};
+use crate::token::{TiktokenCounter, TokenCounter};
 use anyhow::anyhow;
-use std::path::PathBuf;
+use base64::prelude::*;
+use futures::StreamExt;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
 
-const DEFAULT_MAX_CONTEXT: u64 = 16 * 1_024;
+pub const DEFAULT_MAX_CONTEXT: u64 = 16 * 1_024;
+pub const DEFAULT_TABLE_PREVIEW_ROWS: usize = 50;
+const MAX_CONCURRENT_READS: usize = 8;
 
-fn estimate_context_size(content: &str) -> u64 {
-    content.len() as u64 / 4
+#[derive(Debug, thiserror::Error)]
+pub enum PromptError {
+    #[error("Maximum context exceeded ({limit}) while adding {} ({size})", path.as_ref().map_or("document".to_string(), |path| path.display().to_string()))]
+    MaxContextExceeded {
+        path: Option<PathBuf>,
+        size: u64,
+        limit: u64,
+    },
+    #[error("Maximum files reached ({limit}) while adding {}", path.display())]
+    MaxFilesReached { path: PathBuf, limit: usize },
+    #[error("File too large {} ({bytes} bytes, limit {limit})", path.display())]
+    FileTooLarge {
+        path: PathBuf,
+        bytes: u64,
+        limit: u64,
+    },
+    #[error("Skipping binary file {}", .0.display())]
+    BinaryFile(PathBuf),
+    #[error("Context size overflow while adding {size} to {total}")]
+    ContextSizeOverflow { size: u64, total: u64 },
+    #[error("Context template is missing the {CONTEXT_PLACEHOLDER} placeholder")]
+    MissingContextPlaceholder,
+}
+
+fn file_metadata(metadata: &std::fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .map(|modified| humantime::format_rfc3339_seconds(modified).to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    format!("size: {}b, modified: {modified}", metadata.len())
+}
+
+/// Marks where [`PromptBuilder::context_template`] puts the `<files>` and
+/// `<documents>` sections.
+pub const CONTEXT_PLACEHOLDER: &str = "{context}";
+
+/// Rounds `context_size` up to the next power of two starting at 2K, capped at
+/// `ceiling`; used as `num_ctx` when no explicit max context is given.
+pub fn align_context(context_size: u64, ceiling: u64) -> u64 {
+    let mut aligned_context_len = 2 * 1024;
+    while aligned_context_len < context_size && aligned_context_len < ceiling {
+        aligned_context_len = aligned_context_len.saturating_mul(2);
+    }
+    aligned_context_len.min(ceiling)
+}
+
+#[derive(Default)]
+struct TreeNode {
+    is_dir: bool,
+    children: BTreeMap<String, TreeNode>,
+}
+
+impl TreeNode {
+    fn render(&self, prefix: &str, output: &mut String) {
+        let mut children = self.children.iter().peekable();
+        while let Some((name, child)) = children.next() {
+            let is_last = children.peek().is_none();
+            let connector = if is_last { "└── " } else { "├── " };
+            let suffix = if child.is_dir { "/" } else { "" };
+            output.push_str(&format!("{prefix}{connector}{name}{suffix}\n"));
+
+            let child_prefix = if is_last { "    " } else { "│   " };
+            child.render(&format!("{prefix}{child_prefix}"), output);
+        }
+    }
+}
+
+fn render_tree(root: &Path, paths: &[PathBuf]) -> String {
+    let mut tree = TreeNode {
+        is_dir: true,
+        ..Default::default()
+    };
+    for path in paths {
+        let Ok(relative_path) = path.strip_prefix(root) else {
+            continue;
+        };
+        let mut node = &mut tree;
+        for component in relative_path.components() {
+            node = node
+                .children
+                .entry(component.as_os_str().to_string_lossy().to_string())
+                .or_default();
+        }
+        node.is_dir = path.is_dir();
+    }
+
+    let mut output = format!("{}/\n", root.display());
+    tree.render("", &mut output);
+    output
+}
+
+pub fn fence_language(extension: &str) -> String {
+    let language = match extension.to_lowercase().as_str() {
+        "rs" => "rust",
+        "py" | "pyw" | "ipynb" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" | "mts" | "cts" => "typescript",
+        "md" | "markdown" => "markdown",
+        "rb" => "ruby",
+        "sh" | "bash" | "zsh" => "bash",
+        "yml" => "yaml",
+        "kt" | "kts" => "kotlin",
+        "cs" => "csharp",
+        "cc" | "cpp" | "cxx" | "hpp" | "hh" => "cpp",
+        "h" => "c",
+        "htm" => "html",
+        "pl" => "perl",
+        "hs" => "haskell",
+        "ex" | "exs" => "elixir",
+        "erl" => "erlang",
+        "ps1" => "powershell",
+        "txt" => "text",
+        "xlsx" | "xlsm" | "xls" | "ods" => "csv",
+        _ => return extension.to_string(),
+    };
+    language.to_string()
+}
+
+/// Lists `aliases` on a single `aliases: a, b` line under the `path:` line of a
+/// fenced file, replacing the previous list.
+fn set_aliases_line(fenced_content: &mut String, aliases: &[PathBuf]) {
+    let path_line_end = fenced_content.find('\n').unwrap_or(fenced_content.len());
+    if fenced_content[path_line_end..].starts_with("\naliases: ") {
+        let aliases_line_end = fenced_content[path_line_end + 1..]
+            .find('\n')
+            .map_or(fenced_content.len(), |end| path_line_end + 1 + end);
+        fenced_content.replace_range(path_line_end..aliases_line_end, "");
+    }
+    if !aliases.is_empty() {
+        let aliases = aliases
+            .iter()
+            .map(|alias| alias.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ");
+        fenced_content.insert_str(path_line_end, &format!("\naliases: {aliases}"));
+    }
+}
+
+fn number_lines(content: &str) -> String {
+    let width = content.lines().count().to_string().len();
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| format!("{:>width$}| {line}", index + 1))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn decode_text(path: &Path, bytes: Vec<u8>, lossy: bool) -> anyhow::Result<(String, bool)> {
+    let utf16_units = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        bytes
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]))
+            .collect::<Vec<_>>()
+    };
+    let decode_utf16 = |units: Vec<u16>| match String::from_utf16(&units) {
+        Ok(content) => Ok((content, false)),
+        Err(_) if lossy => Ok((String::from_utf16_lossy(&units), true)),
+        Err(error) => Err(anyhow!("Invalid UTF-16 in {}: {error}", path.display())),
+    };
+
+    if let Some(bytes) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        decode_utf16(utf16_units(bytes, u16::from_le_bytes))
+    } else if let Some(bytes) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        decode_utf16(utf16_units(bytes, u16::from_be_bytes))
+    } else {
+        let bytes = match bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+            Some(bytes) => bytes.to_vec(),
+            None => bytes,
+        };
+        match String::from_utf8(bytes) {
+            Ok(content) => Ok((content, false)),
+            Err(error) if lossy => {
+                Ok((String::from_utf8_lossy(error.as_bytes()).into_owned(), true))
+            }
+            Err(error) => Err(anyhow!("Invalid UTF-8 in {}: {error}", path.display())),
+        }
+    }
+}
+
+/// What [`PromptBuilder::add_file_content`] put into the prompt for one file.
+#[derive(Debug, Clone)]
+pub struct AddedFile {
+    /// Bytes of fenced text added, or those the `aliases:` line grew by for duplicates.
+    pub bytes: u64,
+    pub est_tokens: u64,
+    /// Fence language (e.g. `rust`, `csv`).
+    pub language: String,
+    pub truncated: bool,
 }
 
 #[derive(Debug)]
+pub struct FileContent {
+    pub path: PathBuf,
+    pub extension: String,
+    pub content: String,
+    pub lossy: bool,
+    /// `size: Nb, modified: <RFC3339>`, when the reader was asked for it.
+    pub metadata: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileReader {
+    lossy_decode: bool,
+    max_file_bytes: Option<u64>,
+    notebook_markdown: bool,
+    table_preview_rows: usize,
+    metadata: bool,
+}
+
+impl Default for FileReader {
+    fn default() -> Self {
+        Self {
+            lossy_decode: false,
+            max_file_bytes: None,
+            notebook_markdown: false,
+            table_preview_rows: DEFAULT_TABLE_PREVIEW_ROWS,
+            metadata: false,
+        }
+    }
+}
+
+impl FileReader {
+    pub fn lossy_decode(mut self, flag: bool) -> Self {
+        self.lossy_decode = flag;
+        self
+    }
+
+    pub fn max_file_bytes(mut self, value: Option<u64>) -> Self {
+        self.max_file_bytes = value;
+        self
+    }
+
+    /// Jupyter notebooks are read as their code cells only, unless this also keeps
+    /// the markdown cells, as `#` comments.
+    pub fn notebook_markdown(mut self, flag: bool) -> Self {
+        self.notebook_markdown = flag;
+        self
+    }
+
+    /// Spreadsheets are read as every sheet's first row and this many more.
+    pub fn table_preview_rows(mut self, rows: usize) -> Self {
+        self.table_preview_rows = rows;
+        self
+    }
+
+    /// Also reports each file's size and modification time, see [`FileContent::metadata`].
+    pub fn metadata(mut self, flag: bool) -> Self {
+        self.metadata = flag;
+        self
+    }
+
+    pub async fn read(&self, path: PathBuf) -> anyhow::Result<FileContent> {
+        if let Some(max_file_bytes) = self.max_file_bytes {
+            let file_bytes = tokio::fs::metadata(&path).await?.len();
+            if file_bytes > max_file_bytes {
+                return Err(PromptError::FileTooLarge {
+                    path,
+                    bytes: file_bytes,
+                    limit: max_file_bytes,
+                }
+                .into());
+            }
+        }
+
+        // Lowercased once, so `REPORT.PDF` and `Page.HTML` are extracted like their
+        // lowercase twins.
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let mut lossy = false;
+        let content = match &extension[..] {
+            "pdf" => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || pdf_extract::extract_text(path)).await??
+            }
+            "docx" => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || extract::docx_text(path)).await??
+            }
+            "epub" => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || extract::epub_text(path)).await??
+            }
+            spreadsheet if extract::SPREADSHEET_EXTENSIONS.contains(&spreadsheet) => {
+                let path = path.clone();
+                let max_rows = self.table_preview_rows;
+                tokio::task::spawn_blocking(move || extract::spreadsheet_text(path, max_rows))
+                    .await??
+            }
+            _ => {
+                let bytes = tokio::fs::read(&path).await?;
+                if looks_binary(&bytes[..bytes.len().min(8 * 1024)]) {
+                    return Err(PromptError::BinaryFile(path).into());
+                }
+                let content;
+                (content, lossy) = decode_text(&path, bytes, self.lossy_decode)?;
+                match &extension[..] {
+                    "html" | "htm" => extract::html_text(&content)?,
+                    "ipynb" => extract::notebook_text(&content, self.notebook_markdown)?,
+                    _ => content,
+                }
+            }
+        };
+
+        let metadata = if self.metadata {
+            Some(file_metadata(&tokio::fs::metadata(&path).await?))
+        } else {
+            None
+        };
+
+        Ok(FileContent {
+            path,
+            extension,
+            content,
+            lossy,
+            metadata,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct PromptStats {
     pub file_count: usize,
     pub document_count: usize,
     pub context_size_estimated: u64,
     pub prompt_context_size_estimated: u64,
     pub max_context: u64,
+    pub max_files: Option<usize>,
+    pub skipped_files: Vec<PathBuf>,
+    pub truncated_files: Vec<PathBuf>,
+    pub lossy_files: Vec<PathBuf>,
+    pub oversized_files: Vec<PathBuf>,
+    pub deduplicated_files: usize,
+    pub image_count: usize,
+}
+
+/// Order of the files in the built prompt; models tend to attend most to the
+/// context closest to the question, which comes last.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileOrder {
+    #[default]
+    Insertion,
+    PathAlpha,
+    SizeAsc,
+    SizeDesc,
+}
+
+/// What to do with a file that doesn't fit the remaining context budget.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChunkStrategy {
+    /// Skip the file
+    #[default]
+    Whole,
+    /// Keep the longest beginning of the file that fits
+    FirstFit,
+    /// Keep the window of the file that fits and mentions the prompt's terms most
+    KeywordWindow,
 }
 
 pub struct PromptBuilder {
     prompt: String,
     files: Vec<(PathBuf, String)>,
     documents: Vec<String>,
+    skipped_files: Vec<PathBuf>,
+    truncated_files: Vec<PathBuf>,
+    lossy_files: Vec<PathBuf>,
+    oversized_files: Vec<PathBuf>,
+    content_hashes: HashMap<u64, PathBuf>,
+    /// Paths of the files found identical to each added file, in order.
+    aliases: HashMap<PathBuf, Vec<PathBuf>>,
+    deduplicated_files: usize,
+    images: Vec<String>,
     context_size_estimated: u64,
     max_context: Option<u64>,
+    max_context_ceiling: Option<u64>,
+    max_files: Option<usize>,
+    chunk_strategy: ChunkStrategy,
+    strip_comments: bool,
+    definitions_only: bool,
+    plain_markdown: bool,
+    line_numbers: bool,
+    table_preview_rows: usize,
+    order_by: FileOrder,
+    context_template: Option<String>,
+    reader: FileReader,
+    token_counter: Box<dyn TokenCounter>,
 }
 
 impl PromptBuilder {
@@ -30,65 +411,671 @@ impl PromptBuilder {
             prompt,
             files: vec![],
             documents: vec![],
+            skipped_files: vec![],
+            truncated_files: vec![],
+            lossy_files: vec![],
+            oversized_files: vec![],
+            content_hashes: HashMap::new(),
+            aliases: HashMap::new(),
+            deduplicated_files: 0,
+            images: vec![],
             context_size_estimated: 0,
             max_context: None,
+            max_context_ceiling: None,
+            max_files: None,
+            chunk_strategy: ChunkStrategy::default(),
+            strip_comments: false,
+            definitions_only: false,
+            plain_markdown: false,
+            line_numbers: false,
+            table_preview_rows: DEFAULT_TABLE_PREVIEW_ROWS,
+            order_by: FileOrder::default(),
+            context_template: None,
+            reader: FileReader::default(),
+            token_counter: Box::new(TiktokenCounter::default()),
         }
     }
 
+    pub fn set_prompt(&mut self, prompt: String) {
+        self.prompt = prompt;
+    }
+
     pub fn max_context(mut self, value: Option<u64>) -> Self {
         self.max_context = value;
         self
     }
 
-    pub async fn add_file(&mut self, path: PathBuf) -> anyhow::Result<u64> {
-        let extension = path
-            .extension()
-            .map(|extension| extension.to_string_lossy().to_string())
-            .unwrap_or_default();
+    pub fn max_file_bytes(mut self, value: Option<u64>) -> Self {
+        self.reader = self.reader.max_file_bytes(value);
+        self
+    }
+
+    /// Largest `num_ctx` the automatic alignment picks; [`DEFAULT_MAX_CONTEXT`] when `None`.
+    pub fn max_context_ceiling(mut self, value: Option<u64>) -> Self {
+        self.max_context_ceiling = value;
+        self
+    }
+
+    /// Whether `max_context` or `max_context_ceiling` was set, so the defaults aren't used.
+    pub fn has_context_limits(&self) -> bool {
+        self.max_context.is_some() || self.max_context_ceiling.is_some()
+    }
+
+    fn context_ceiling(&self) -> u64 {
+        self.max_context_ceiling.unwrap_or(DEFAULT_MAX_CONTEXT)
+    }
+
+    pub fn max_files(mut self, value: Option<usize>) -> Self {
+        self.max_files = value;
+        self
+    }
+
+    /// Shorthand for [`ChunkStrategy::FirstFit`] when set.
+    pub fn truncate_files(mut self, flag: bool) -> Self {
+        if flag {
+            self.chunk_strategy = ChunkStrategy::FirstFit;
+        }
+        self
+    }
+
+    /// With [`ChunkStrategy::KeywordWindow`], the prompt has to be set before the
+    /// files are added, since their windows are picked by its terms.
+    pub fn chunk_strategy(mut self, chunk_strategy: ChunkStrategy) -> Self {
+        self.chunk_strategy = chunk_strategy;
+        self
+    }
+
+    pub fn strip_comments(mut self, flag: bool) -> Self {
+        self.strip_comments = flag;
+        self
+    }
+
+    /// Keeps only the declarations and doc comments of Rust files, eliding function
+    /// bodies; files that don't parse are sent whole.
+    pub fn definitions_only(mut self, flag: bool) -> Self {
+        self.definitions_only = flag;
+        self
+    }
+
+    pub fn plain_markdown(mut self, flag: bool) -> Self {
+        self.plain_markdown = flag;
+        self
+    }
+
+    /// Prefixes each line with its number in the file; files changed by comment
+    /// stripping, `definitions_only`, plain Markdown or a table preview are sent
+    /// unnumbered.
+    pub fn line_numbers(mut self, flag: bool) -> Self {
+        self.line_numbers = flag;
+        self
+    }
+
+    /// CSV and TSV files are sent as a table of their header and this many rows,
+    /// followed by a count of the rows left out; spreadsheets are cut the same way.
+    pub fn table_preview_rows(mut self, rows: usize) -> Self {
+        self.table_preview_rows = rows;
+        self.reader = self.reader.table_preview_rows(rows);
+        self
+    }
+
+    /// Replaces the `<context>` framing placed after the prompt; every
+    /// [`CONTEXT_PLACEHOLDER`] in `template` is replaced by the files and documents.
+    pub fn context_template(mut self, template: impl Into<String>) -> Result<Self, PromptError> {
+        let template = template.into();
+        if !template.contains(CONTEXT_PLACEHOLDER) {
+            return Err(PromptError::MissingContextPlaceholder);
+        }
+        self.context_template = Some(template);
+        Ok(self)
+    }
+
+    pub fn order_by(mut self, order_by: FileOrder) -> Self {
+        self.order_by = order_by;
+        self
+    }
+
+    pub fn lossy_decode(mut self, flag: bool) -> Self {
+        self.reader = self.reader.lossy_decode(flag);
+        self
+    }
+
+    pub fn notebook_markdown(mut self, flag: bool) -> Self {
+        self.reader = self.reader.notebook_markdown(flag);
+        self
+    }
+
+    /// Adds a `size: Nb, modified: <RFC3339>` line under each file's `path:` line.
+    pub fn file_metadata(mut self, flag: bool) -> Self {
+        self.reader = self.reader.metadata(flag);
+        self
+    }
+
+    pub fn token_counter(mut self, counter: impl TokenCounter + 'static) -> Self {
+        self.token_counter = Box::new(counter);
+        self
+    }
+
+    pub fn reader(&self) -> FileReader {
+        self.reader.clone()
+    }
+
+    pub async fn add_file(&mut self, path: PathBuf) -> anyhow::Result<AddedFile> {
+        let file = self.reader.read(path.clone()).await.inspect_err(|error| {
+            if let Some(PromptError::FileTooLarge { .. }) = error.downcast_ref() {
+                self.oversized_files.push(path);
+            }
+        })?;
+        Ok(self.add_file_content(file)?)
+    }
+
+    /// Attaches a PNG, JPEG, GIF or WebP image, sent base64-encoded alongside the
+    /// prompt; only vision models accept images.
+    pub async fn add_image(&mut self, path: &Path) -> anyhow::Result<()> {
+        let bytes = tokio::fs::read(path)
+            .await
+            .map_err(|error| anyhow!("Could not read image {}: {error}", path.display()))?;
+        if image_mime_type(&bytes).is_none() {
+            return Err(anyhow!(
+                "Unsupported image {}: expected PNG, JPEG, GIF or WebP",
+                path.display()
+            ));
+        }
+        self.images.push(BASE64_STANDARD.encode(bytes));
+        Ok(())
+    }
+
+    /// Reads `paths` a few at a time and adds them in order, calling `on_file` with
+    /// the outcome of each; stops early once `max_files` is reached.
+    pub async fn add_files<I, F>(&mut self, paths: I, mut on_file: F) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = PathBuf>,
+        F: FnMut(&Self, &Path, &anyhow::Result<AddedFile>),
+    {
+        let reader = &self.reader();
+        let mut files = futures::stream::iter(paths)
+            .map(|path| async move { (path.clone(), reader.read(path).await) })
+            .buffered(MAX_CONCURRENT_READS);
+        while let Some((path, file)) = files.next().await {
+            if let Err(error) = &file
+                && let Some(PromptError::FileTooLarge { .. }) = error.downcast_ref()
+            {
+                self.add_oversized_file(path.clone());
+            }
+            let result = file.and_then(|file| Ok(self.add_file_content(file)?));
+            on_file(self, &path, &result);
+            if let Err(error) = result {
+                match error.downcast_ref() {
+                    Some(PromptError::MaxFilesReached { .. }) => break,
+                    Some(PromptError::ContextSizeOverflow { .. }) => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_oversized_file(&mut self, path: PathBuf) {
+        self.oversized_files.push(path);
+    }
+
+    pub fn add_content(
+        &mut self,
+        path_label: &str,
+        extension: &str,
+        content: String,
+    ) -> Result<AddedFile, PromptError> {
+        self.add_file_content(FileContent {
+            path: PathBuf::from(path_label),
+            extension: extension.to_string(),
+            content,
+            lossy: false,
+            metadata: None,
+        })
+    }
+
+    pub fn add_file_content(&mut self, file: FileContent) -> Result<AddedFile, PromptError> {
+        let FileContent {
+            path,
+            extension,
+            content,
+            lossy,
+            metadata,
+        } = file;
         let path_as_string = path.to_string_lossy().to_string();
 
-        let content = if extension == "pdf" {
-            pdf_extract::extract_text(&path)?
+        let content_hash = xxh3_64(content.as_bytes());
+        if let Some(original_path) = self.content_hashes.get(&content_hash).cloned()
+            && let Some(index) = self
+                .files
+                .iter()
+                .position(|(file_path, ..)| *file_path == original_path)
+        {
+            let mut aliases = self
+                .aliases
+                .get(&original_path)
+                .cloned()
+                .unwrap_or_default();
+            aliases.push(path.clone());
+            let original_content = &self.files[index].1;
+            let mut aliased_content = original_content.clone();
+            set_aliases_line(&mut aliased_content, &aliases);
+            let alias_context_size = self
+                .token_counter
+                .count(&aliased_content)
+                .saturating_sub(self.token_counter.count(original_content));
+            if let Some(max_context) = self.max_context.or(Some(self.context_ceiling()))
+                && alias_context_size > self.remaining_context(max_context)
+            {
+                self.skipped_files.push(path.clone());
+                return Err(PromptError::MaxContextExceeded {
+                    path: Some(path),
+                    size: alias_context_size,
+                    limit: max_context,
+                });
+            }
+            self.context_size_estimated = self.checked_context_size(alias_context_size)?;
+
+            let bytes = aliased_content.len().saturating_sub(original_content.len()) as u64;
+            self.files[index].1 = aliased_content;
+            self.aliases.insert(original_path, aliases);
+            self.deduplicated_files += 1;
+            return Ok(AddedFile {
+                bytes,
+                est_tokens: alias_context_size,
+                language: fence_language(&extension),
+                truncated: false,
+            });
+        }
+
+        if let Some(max_files) = self.max_files
+            && self.files.len() >= max_files
+        {
+            return Err(PromptError::MaxFilesReached {
+                path,
+                limit: max_files,
+            });
+        }
+
+        let original_content = self.line_numbers.then(|| content.clone());
+        let content = if self.definitions_only && extension.eq_ignore_ascii_case("rs") {
+            extract::rust_definitions(&content).unwrap_or(content)
         } else {
-            tokio::fs::read_to_string(&path).await?
+            content
         };
+        let content = if self.strip_comments {
+            comments::strip_comments(&content, &extension)
+        } else {
+            content
+        };
+        let is_plain_markdown =
+            self.plain_markdown && matches!(&extension.to_lowercase()[..], "md" | "markdown");
+        let content = if is_plain_markdown {
+            extract::markdown_text(&content)
+        } else {
+            content
+        };
+        let delimiter = match &extension.to_lowercase()[..] {
+            "csv" => Some(b','),
+            "tsv" => Some(b'\t'),
+            _ => None,
+        };
+        let table_preview = delimiter.and_then(|delimiter| {
+            extract::table_preview(&content, delimiter, self.table_preview_rows)
+        });
+        let is_table_preview = table_preview.is_some();
+        let content = table_preview.unwrap_or(content);
+        // Rewritten lines no longer match the file's, so only untouched files are numbered.
+        let content =
+            if original_content.is_some_and(|original_content| original_content == content) {
+                number_lines(&content)
+            } else {
+                content
+            };
 
-        let content = format!(
-            "path: {}\n```{}\n{}\n```",
-            path_as_string, extension, content
-        );
+        let language = if is_plain_markdown {
+            "text".to_string()
+        } else if is_table_preview {
+            "markdown".to_string()
+        } else {
+            fence_language(&extension)
+        };
+        let path_label = match &metadata {
+            Some(metadata) => format!("{path_as_string}\n{metadata}"),
+            None => path_as_string.clone(),
+        };
+        let mut fenced_content = format!("path: {}\n```{}\n{}\n```", path_label, language, content);
 
-        let content_context_size = estimate_context_size(&content);
-        if let Some(max_context) = self.max_context.or(Some(DEFAULT_MAX_CONTEXT))
-            && (self.context_size_estimated + content_context_size) > max_context
+        let mut content_context_size = self.token_counter.count(&fenced_content);
+        let mut truncated = false;
+        if let Some(max_context) = self.max_context.or(Some(self.context_ceiling()))
+            && content_context_size > self.remaining_context(max_context)
         {
-            return Err(anyhow!(
-                "Maximum context exceeded ({max_context:?}) while adding {path_as_string} ({content_context_size})",
-            ));
+            let context_budget = self.remaining_context(max_context);
+            let chunked = match self.chunk_strategy {
+                ChunkStrategy::Whole => None,
+                ChunkStrategy::FirstFit => {
+                    self.truncate_content(&path_label, &language, &content, context_budget)
+                }
+                ChunkStrategy::KeywordWindow => {
+                    self.keyword_window(&path_label, &language, &content, context_budget)
+                }
+            };
+
+            let Some((truncated_content, truncated_context_size)) = chunked else {
+                self.skipped_files.push(path.clone());
+                return Err(PromptError::MaxContextExceeded {
+                    path: Some(path),
+                    size: content_context_size,
+                    limit: max_context,
+                });
+            };
+            fenced_content = truncated_content;
+            content_context_size = truncated_context_size;
+            self.truncated_files.push(path.clone());
+            truncated = true;
         }
-        self.context_size_estimated += content_context_size;
+        self.context_size_estimated = self.checked_context_size(content_context_size)?;
 
-        self.files.push((path, content));
+        self.content_hashes.insert(content_hash, path.clone());
+        if lossy {
+            self.lossy_files.push(path.clone());
+        }
+        let added = AddedFile {
+            bytes: fenced_content.len() as u64,
+            est_tokens: content_context_size,
+            language,
+            truncated,
+        };
+        self.files.push((path, fenced_content));
 
-        Ok(content_context_size)
+        Ok(added)
+    }
+
+    fn remaining_context(&self, max_context: u64) -> u64 {
+        max_context.saturating_sub(self.context_size_estimated)
+    }
+
+    fn checked_context_size(&self, content_context_size: u64) -> Result<u64, PromptError> {
+        self.context_size_estimated
+            .checked_add(content_context_size)
+            .ok_or(PromptError::ContextSizeOverflow {
+                size: content_context_size,
+                total: self.context_size_estimated,
+            })
+    }
+
+    fn truncate_content(
+        &self,
+        path_as_string: &str,
+        language: &str,
+        content: &str,
+        context_budget: u64,
+    ) -> Option<(String, u64)> {
+        let fence = |kept: usize| {
+            format!(
+                "path: {}\n```{}\n{}\n...[truncated {} bytes]...\n```",
+                path_as_string,
+                language,
+                &content[..kept],
+                content.len() - kept
+            )
+        };
+
+        // `low` stays on a char boundary so every pass moves past `middle`.
+        let mut best = None;
+        let next_boundary =
+            |index: usize| index + content[index..].chars().next().map_or(1, char::len_utf8);
+        let (mut low, mut high) = (next_boundary(0), content.len());
+        while low <= high {
+            let middle = content.floor_char_boundary(low + (high - low) / 2);
+            let truncated_content = fence(middle);
+            let truncated_context_size = self.token_counter.count(&truncated_content);
+            if truncated_context_size <= context_budget {
+                if middle > 0 {
+                    best = Some((truncated_content, truncated_context_size));
+                }
+                low = next_boundary(middle);
+            } else if middle == 0 {
+                break;
+            } else {
+                high = middle - 1;
+            }
+        }
+        best
+    }
+
+    fn keyword_window(
+        &self,
+        path_as_string: &str,
+        language: &str,
+        content: &str,
+        context_budget: u64,
+    ) -> Option<(String, u64)> {
+        let fence = |start: usize, end: usize| {
+            let before = if start > 0 {
+                format!("...[skipped {start} bytes]...\n")
+            } else {
+                String::new()
+            };
+            let after = if end < content.len() {
+                format!("\n...[skipped {} bytes]...", content.len() - end)
+            } else {
+                String::new()
+            };
+            format!(
+                "path: {}\n```{}\n{}{}{}\n```",
+                path_as_string,
+                language,
+                before,
+                &content[start..end],
+                after
+            )
+        };
+
+        let (first_window, _) =
+            self.truncate_content(path_as_string, language, content, context_budget)?;
+        let window_len = first_window.len().min(content.len());
+        let step = (window_len / 2).max(1);
+
+        let prompt = self.prompt.to_lowercase();
+        let mut terms = prompt
+            .split(|char: char| !char.is_alphanumeric() && char != '_')
+            .filter(|term| term.chars().count() >= 3)
+            .collect::<Vec<_>>();
+        terms.sort_unstable();
+        terms.dedup();
+
+        let mut best: Option<(usize, usize, usize)> = None;
+        let mut offset = 0;
+        while offset < content.len() {
+            let start = content[..content.floor_char_boundary(offset)]
+                .rfind('\n')
+                .map_or(0, |position| position + 1);
+            let end = content.floor_char_boundary((start + window_len).min(content.len()));
+            let window = content[start..end].to_lowercase();
+            let score = terms.iter().map(|term| window.matches(term).count()).sum();
+            if best.is_none_or(|(best_score, _, _)| score > best_score) {
+                best = Some((score, start, end));
+            }
+            if end == content.len() {
+                break;
+            }
+            offset += step;
+        }
+
+        let (_, start, mut end) = best?;
+        loop {
+            let windowed_content = fence(start, end);
+            let windowed_context_size = self.token_counter.count(&windowed_content);
+            if windowed_context_size <= context_budget {
+                return Some((windowed_content, windowed_context_size));
+            }
+            let shrunk = content.floor_char_boundary(end - (end - start) / 10 - 1);
+            if shrunk <= start {
+                return None;
+            }
+            end = shrunk;
+        }
     }
 
-    pub fn add_document(&mut self, content: String) -> anyhow::Result<u64> {
-        let content_context_size = estimate_context_size(&content);
-        if let Some(max_context) = self.max_context.or(Some(DEFAULT_MAX_CONTEXT))
-            && (self.context_size_estimated + content_context_size) > max_context
+    pub fn add_document(&mut self, content: String) -> Result<u64, PromptError> {
+        let content_context_size = self.token_counter.count(&content);
+        if let Some(max_context) = self.max_context.or(Some(self.context_ceiling()))
+            && content_context_size > self.remaining_context(max_context)
         {
-            return Err(anyhow!(
-                "Maximum context exceeded {max_context:?} while adding document ({content_context_size})"
-            ));
+            return Err(PromptError::MaxContextExceeded {
+                path: None,
+                size: content_context_size,
+                limit: max_context,
+            });
         }
-        self.context_size_estimated += content_context_size;
+        self.context_size_estimated = self.checked_context_size(content_context_size)?;
 
         self.documents.push(content);
 
         Ok(content_context_size)
     }
 
+    pub fn add_tree<P: AsRef<Path>>(
+        &mut self,
+        root: &Path,
+        max_depth: usize,
+        excludes: &[P],
+    ) -> anyhow::Result<u64> {
+        let paths = FileSearcher::new(root)
+            .gitignore(true)
+            .max_depth(max_depth)
+            .excludes(excludes)
+            .into_iter()
+            .filter_map(|result| result.ok())
+            .collect::<Vec<_>>();
+
+        Ok(self.add_document(format!("<tree>\n{}</tree>", render_tree(root, &paths)))?)
+    }
+
+    pub fn add_diff<P: AsRef<Path>>(&mut self, paths: &[P]) -> anyhow::Result<u64> {
+        let output = std::process::Command::new("git")
+            .args(["diff", "--no-color", "--no-ext-diff", "--"])
+            .args(paths.iter().map(|path| path.as_ref()))
+            .output()
+            .map_err(|error| anyhow!("Could not run git diff: {error}"))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git diff failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+            ));
+        }
+
+        let diff = String::from_utf8_lossy(&output.stdout);
+        if diff.trim().is_empty() {
+            return Ok(0);
+        }
+        Ok(self.add_document(format!("<diff>\n{diff}</diff>"))?)
+    }
+
+    /// Removes an added file, or a duplicate listed among its aliases; removing a file
+    /// with aliases puts its first alias in its place.
+    pub fn remove_file(&mut self, path: &Path) -> bool {
+        let Some(index) = self
+            .files
+            .iter()
+            .position(|(file_path, ..)| file_path == path)
+        else {
+            return self.remove_alias(path);
+        };
+
+        let (_, content) = self.files.remove(index);
+        self.context_size_estimated = self
+            .context_size_estimated
+            .saturating_sub(self.token_counter.count(&content));
+        let mut aliases = self.aliases.remove(path).unwrap_or_default();
+        if aliases.is_empty() {
+            self.truncated_files.retain(|file_path| file_path != path);
+            self.lossy_files.retain(|file_path| file_path != path);
+            self.content_hashes.retain(|_, file_path| file_path != path);
+            return true;
+        }
+
+        let promoted = aliases.remove(0);
+        let mut content = content.replacen(
+            &format!("path: {}", path.to_string_lossy()),
+            &format!("path: {}", promoted.to_string_lossy()),
+            1,
+        );
+        set_aliases_line(&mut content, &aliases);
+        self.context_size_estimated = self
+            .context_size_estimated
+            .saturating_add(self.token_counter.count(&content));
+        for file_path in self
+            .truncated_files
+            .iter_mut()
+            .chain(&mut self.lossy_files)
+            .chain(self.content_hashes.values_mut())
+            .filter(|file_path| *file_path == path)
+        {
+            *file_path = promoted.clone();
+        }
+        if !aliases.is_empty() {
+            self.aliases.insert(promoted.clone(), aliases);
+        }
+        self.files.insert(index, (promoted, content));
+        self.deduplicated_files -= 1;
+        true
+    }
+
+    fn remove_alias(&mut self, path: &Path) -> bool {
+        let Some((original_path, aliases)) = self
+            .aliases
+            .iter_mut()
+            .find(|(_, aliases)| aliases.iter().any(|alias| alias == path))
+        else {
+            return false;
+        };
+        aliases.retain(|alias| alias != path);
+        let original_path = original_path.clone();
+        let aliases = aliases.clone();
+        if aliases.is_empty() {
+            self.aliases.remove(&original_path);
+        }
+
+        if let Some((_, content)) = self
+            .files
+            .iter_mut()
+            .find(|(file_path, ..)| *file_path == original_path)
+        {
+            let previous_context_size = self.token_counter.count(content);
+            set_aliases_line(content, &aliases);
+            self.context_size_estimated = self
+                .context_size_estimated
+                .saturating_sub(previous_context_size)
+                .saturating_add(self.token_counter.count(content));
+        }
+        self.deduplicated_files -= 1;
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.documents.clear();
+        self.skipped_files.clear();
+        self.truncated_files.clear();
+        self.lossy_files.clear();
+        self.oversized_files.clear();
+        self.content_hashes.clear();
+        self.aliases.clear();
+        self.deduplicated_files = 0;
+        self.images.clear();
+        self.context_size_estimated = 0;
+    }
+
+    /// Estimates `text`'s tokens with the counter used for the context budget.
+    pub fn count_tokens(&self, text: &str) -> u64 {
+        self.token_counter.count(text)
+    }
+
     pub fn files(&self) -> &Vec<(PathBuf, String)> {
         &self.files
     }
@@ -97,14 +1084,31 @@ impl PromptBuilder {
         &self.documents
     }
 
+    pub fn images(&self) -> &Vec<String> {
+        &self.images
+    }
+
+    pub fn lossy_files(&self) -> &Vec<PathBuf> {
+        &self.lossy_files
+    }
+
     pub fn build(&self) -> anyhow::Result<(String, PromptStats)> {
         let mut context: Vec<String> = vec![];
 
         if !self.files.is_empty() {
+            let mut files = self.files.iter().collect::<Vec<_>>();
+            match self.order_by {
+                FileOrder::Insertion => {}
+                FileOrder::PathAlpha => files.sort_by(|(a, _), (b, _)| a.cmp(b)),
+                FileOrder::SizeAsc => files.sort_by_key(|(_, content)| content.len()),
+                FileOrder::SizeDesc => {
+                    files.sort_by_key(|(_, content)| std::cmp::Reverse(content.len()))
+                }
+            }
             context.push(format!(
                 "<files>\n{}\n</files>",
-                self.files
-                    .iter()
+                files
+                    .into_iter()
                     .fold(String::new(), |acc, (.., content)| format!(
                         "{acc}\n{content}"
                     ))
@@ -121,35 +1125,22 @@ impl PromptBuilder {
         let prompt_context_size_estimated;
         if context.is_empty() {
             prompt = self.prompt.clone();
-            prompt_context_size_estimated = estimate_context_size(&prompt);
+            prompt_context_size_estimated = self.token_counter.count(&prompt);
         } else {
-            prompt = [
-                self.prompt.clone(),
-                format!(
+            let context = match &self.context_template {
+                Some(template) => template.replace(CONTEXT_PLACEHOLDER, &context.join("\n")),
+                None => format!(
                     include_str!("prompt_context_template.in"),
                     context.join("\n")
                 ),
-            ]
-            .join("\n");
-            prompt_context_size_estimated = estimate_context_size(&prompt);
+            };
+            prompt = [self.prompt.clone(), context].join("\n");
+            prompt_context_size_estimated = self.token_counter.count(&prompt);
         }
 
-        let max_context = match self.max_context {
-            Some(max_context) => max_context,
-            _ if prompt_context_size_estimated > DEFAULT_MAX_CONTEXT => DEFAULT_MAX_CONTEXT,
-            _ => {
-                let mut aligned_context_len = 2 * 1024;
-                while aligned_context_len < prompt_context_size_estimated {
-                    aligned_context_len *= 2;
-                }
-
-                if aligned_context_len > DEFAULT_MAX_CONTEXT {
-                    DEFAULT_MAX_CONTEXT
-                } else {
-                    aligned_context_len
-                }
-            }
-        };
+        let max_context = self.max_context.unwrap_or_else(|| {
+            align_context(prompt_context_size_estimated, self.context_ceiling())
+        });
 
         Ok((
             prompt,
@@ -159,7 +1150,454 @@ impl PromptBuilder {
                 context_size_estimated: self.context_size_estimated,
                 prompt_context_size_estimated,
                 max_context,
+                max_files: self.max_files,
+                skipped_files: self.skipped_files.clone(),
+                truncated_files: self.truncated_files.clone(),
+                lossy_files: self.lossy_files.clone(),
+                oversized_files: self.oversized_files.clone(),
+                deduplicated_files: self.deduplicated_files,
+                image_count: self.images.len(),
             },
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::HeuristicCounter;
+
+    fn truncating_builder(max_context: u64, chunk_strategy: ChunkStrategy) -> PromptBuilder {
+        PromptBuilder::new("where is 漢字 used?".to_string())
+            .max_context(Some(max_context))
+            .chunk_strategy(chunk_strategy)
+            .token_counter(HeuristicCounter)
+    }
+
+    #[test]
+    fn first_fit_truncates_multi_byte_content_on_char_boundaries() {
+        let mut prompt_builder = truncating_builder(12, ChunkStrategy::FirstFit);
+        let result = prompt_builder.add_content("x.txt", "txt", "漢".repeat(10));
+        assert!(matches!(
+            result,
+            Err(PromptError::MaxContextExceeded { .. })
+        ));
+
+        let mut prompt_builder = truncating_builder(20, ChunkStrategy::FirstFit);
+        let added = prompt_builder
+            .add_content("x.txt", "txt", "漢".repeat(20))
+            .unwrap();
+        assert!(added.truncated);
+        let (_, content) = &prompt_builder.files()[0];
+        assert!(content.contains("\n漢"));
+        assert!(content.contains("...[truncated "));
+    }
+
+    #[test]
+    fn fence_language_maps_extensions() {
+        for (extension, language) in [
+            ("rs", "rust"),
+            ("PY", "python"),
+            ("ipynb", "python"),
+            ("mjs", "javascript"),
+            ("cts", "typescript"),
+            ("yml", "yaml"),
+            ("hpp", "cpp"),
+            ("h", "c"),
+            ("txt", "text"),
+            ("xlsx", "csv"),
+            ("go", "go"),
+            ("toml", "toml"),
+        ] {
+            assert_eq!(fence_language(extension), language, "{extension}");
+        }
+    }
+
+    #[test]
+    fn file_without_extension_gets_a_bare_fence() {
+        assert_eq!(fence_language(""), "");
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        prompt_builder
+            .add_content("Makefile", "", "all:\n\tcargo build".to_string())
+            .unwrap();
+        assert_eq!(
+            prompt_builder.files()[0].1,
+            "path: Makefile\n```\nall:\n\tcargo build\n```"
+        );
+    }
+
+    #[test]
+    fn align_context_doubles_up_to_the_ceiling() {
+        assert_eq!(align_context(100, DEFAULT_MAX_CONTEXT), 2 * 1024);
+        assert_eq!(align_context(5_000, DEFAULT_MAX_CONTEXT), 8 * 1024);
+        assert_eq!(
+            align_context(100_000, DEFAULT_MAX_CONTEXT),
+            DEFAULT_MAX_CONTEXT
+        );
+        assert_eq!(align_context(40_000, 128 * 1024), 64 * 1024);
+        assert_eq!(align_context(u64::MAX, 128 * 1024), 128 * 1024);
+    }
+
+    #[test]
+    fn max_context_override_passes_beyond_the_ceiling() {
+        let prompt_builder = PromptBuilder::new("hi".to_string()).max_context(Some(64 * 1024));
+        let (_, prompt_stats) = prompt_builder.build().unwrap();
+        assert_eq!(prompt_stats.max_context, 64 * 1024);
+
+        let mut prompt_builder = PromptBuilder::new("hi".to_string())
+            .max_context_ceiling(Some(64 * 1024))
+            .token_counter(HeuristicCounter);
+        prompt_builder
+            .add_content("big.txt", "txt", "word ".repeat(30_000))
+            .unwrap();
+        let (_, prompt_stats) = prompt_builder.build().unwrap();
+        assert_eq!(prompt_stats.max_context, 64 * 1024);
+    }
+
+    #[test]
+    fn huge_context_sizes_error_instead_of_wrapping() {
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        prompt_builder.context_size_estimated = u64::MAX - 10;
+
+        assert_eq!(prompt_builder.checked_context_size(10).unwrap(), u64::MAX);
+        assert!(matches!(
+            prompt_builder.checked_context_size(11),
+            Err(PromptError::ContextSizeOverflow {
+                size: 11,
+                total,
+            }) if total == u64::MAX - 10
+        ));
+        assert_eq!(prompt_builder.remaining_context(1_000), 0);
+
+        let result = prompt_builder.add_content("a.txt", "txt", "hello".to_string());
+        assert!(matches!(
+            result,
+            Err(PromptError::MaxContextExceeded { .. })
+        ));
+        assert_eq!(prompt_builder.context_size_estimated, u64::MAX - 10);
+    }
+
+    #[test]
+    fn order_by_sorts_the_files_section() {
+        for (order_by, expected) in [
+            (FileOrder::Insertion, ["c.rs", "a.rs", "b.rs"]),
+            (FileOrder::PathAlpha, ["a.rs", "b.rs", "c.rs"]),
+            (FileOrder::SizeAsc, ["b.rs", "c.rs", "a.rs"]),
+            (FileOrder::SizeDesc, ["a.rs", "c.rs", "b.rs"]),
+        ] {
+            let mut prompt_builder = PromptBuilder::new("order?".to_string()).order_by(order_by);
+            for (path, size) in [("c.rs", 20), ("a.rs", 40), ("b.rs", 1)] {
+                prompt_builder
+                    .add_content(path, "rs", "x".repeat(size))
+                    .unwrap();
+            }
+            let (prompt, _) = prompt_builder.build().unwrap();
+            let mut paths: Vec<_> = ["a.rs", "b.rs", "c.rs"]
+                .into_iter()
+                .map(|path| (prompt.find(&format!("path: {path}")).unwrap(), path))
+                .collect();
+            paths.sort();
+            let paths: Vec<_> = paths.into_iter().map(|(_, path)| path).collect();
+            assert_eq!(paths, expected, "{order_by:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn metadata_line_follows_the_path_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "hello").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000))
+            .unwrap();
+
+        let file = FileReader::default()
+            .metadata(true)
+            .read(path.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            file.metadata.as_deref(),
+            Some("size: 5b, modified: 2023-11-14T22:13:20Z")
+        );
+
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        prompt_builder.add_file_content(file).unwrap();
+        assert_eq!(
+            prompt_builder.files()[0].1,
+            format!(
+                "path: {}\nsize: 5b, modified: 2023-11-14T22:13:20Z\n```text\nhello\n```",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn duplicates_are_listed_on_one_aliases_line() {
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        for path in ["a.rs", "b.rs", "c.rs"] {
+            prompt_builder
+                .add_content(path, "rs", "fn same() {}".to_string())
+                .unwrap();
+        }
+        assert_eq!(prompt_builder.files().len(), 1);
+        assert_eq!(
+            prompt_builder.files()[0].1,
+            "path: a.rs\naliases: b.rs, c.rs\n```rust\nfn same() {}\n```"
+        );
+        let (_, prompt_stats) = prompt_builder.build().unwrap();
+        assert_eq!(prompt_stats.deduplicated_files, 2);
+    }
+
+    #[test]
+    fn duplicates_go_through_the_budget_check() {
+        let mut prompt_builder = PromptBuilder::new(String::new()).token_counter(HeuristicCounter);
+        prompt_builder
+            .add_content("a.rs", "rs", "fn same() {}".to_string())
+            .unwrap();
+        let context_size = prompt_builder.context_size_estimated;
+        prompt_builder.max_context = Some(context_size + 1);
+
+        let result = prompt_builder.add_content(
+            "a/very/long/path/to/an/identical/copy.rs",
+            "rs",
+            "fn same() {}".to_string(),
+        );
+        assert!(matches!(
+            result,
+            Err(PromptError::MaxContextExceeded { .. })
+        ));
+        assert_eq!(prompt_builder.context_size_estimated, context_size);
+        assert!(!prompt_builder.files()[0].1.contains("aliases:"));
+    }
+
+    #[test]
+    fn removing_an_original_promotes_its_first_alias() {
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        for path in ["a.rs", "b.rs"] {
+            prompt_builder
+                .add_content(path, "rs", "fn same() {}".to_string())
+                .unwrap();
+        }
+        assert!(prompt_builder.remove_file(Path::new("a.rs")));
+
+        let (prompt, prompt_stats) = prompt_builder.build().unwrap();
+        assert!(prompt.contains("path: b.rs\n```rust\nfn same() {}\n```"));
+        assert!(!prompt.contains("a.rs"));
+        assert!(!prompt.contains("aliases:"));
+        assert_eq!(prompt_stats.file_count, 1);
+        assert_eq!(prompt_stats.deduplicated_files, 0);
+        assert_eq!(
+            prompt_stats.context_size_estimated,
+            prompt_builder
+                .token_counter
+                .count(&prompt_builder.files()[0].1)
+        );
+
+        assert!(prompt_builder.remove_file(Path::new("b.rs")));
+        let (_, prompt_stats) = prompt_builder.build().unwrap();
+        assert_eq!(prompt_stats.file_count, 0);
+        assert_eq!(prompt_stats.context_size_estimated, 0);
+    }
+
+    #[test]
+    fn removing_an_alias_drops_it_from_the_aliases_line() {
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        for path in ["a.rs", "b.rs", "c.rs"] {
+            prompt_builder
+                .add_content(path, "rs", "fn same() {}".to_string())
+                .unwrap();
+        }
+        assert!(prompt_builder.remove_file(Path::new("b.rs")));
+        assert!(!prompt_builder.remove_file(Path::new("b.rs")));
+
+        assert!(
+            prompt_builder.files()[0]
+                .1
+                .starts_with("path: a.rs\naliases: c.rs\n")
+        );
+        let (_, prompt_stats) = prompt_builder.build().unwrap();
+        assert_eq!(prompt_stats.deduplicated_files, 1);
+    }
+
+    /// A PDF with one page of Helvetica text per entry of `pages`.
+    fn pdf_with_pages(pages: &[&str]) -> Vec<u8> {
+        let page_ids: Vec<_> = (0..pages.len()).map(|index| 4 + 2 * index).collect();
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                page_ids
+                    .iter()
+                    .map(|id| format!("{id} 0 R"))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                pages.len()
+            ),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+        ];
+        for (page, id) in pages.iter().zip(&page_ids) {
+            let content = format!("BT /F1 12 Tf 72 720 Td ({page}) Tj ET");
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] \
+                 /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+                id + 1
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{content}\nendstream",
+                content.len()
+            ));
+        }
+
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = vec![];
+        for (index, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n{object}\nendobj\n", index + 1).bytes());
+        }
+        let xref_offset = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).bytes());
+        for offset in offsets {
+            pdf.extend(format!("{offset:010} 00000 n \n").bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref_offset}\n%%EOF\n",
+                objects.len() + 1
+            )
+            .bytes(),
+        );
+        pdf
+    }
+
+    #[tokio::test]
+    async fn multi_page_pdf_loads_alongside_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf_path = dir.path().join("manual.pdf");
+        std::fs::write(
+            &pdf_path,
+            pdf_with_pages(&["First page", "Second page", "Third page"]),
+        )
+        .unwrap();
+        let mut paths = vec![];
+        for index in 0..8 {
+            let path = dir.path().join(format!("source_{index}.rs"));
+            std::fs::write(&path, format!("fn source_{index}() {{}}")).unwrap();
+            paths.push(path);
+        }
+        paths.insert(3, pdf_path.clone());
+
+        let mut prompt_builder = PromptBuilder::new(String::new());
+        let mut errors = vec![];
+        prompt_builder
+            .add_files(paths.clone(), |_, path, result| {
+                if let Err(error) = result {
+                    errors.push(format!("{path:?}: {error}"));
+                }
+            })
+            .await
+            .unwrap();
+        assert!(errors.is_empty(), "{errors:?}");
+
+        let added: Vec<_> = prompt_builder
+            .files()
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect();
+        assert_eq!(added, paths);
+        let (_, pdf_content) = &prompt_builder.files()[3];
+        let first = pdf_content.find("First page").unwrap();
+        let second = pdf_content.find("Second page").unwrap();
+        let third = pdf_content.find("Third page").unwrap();
+        assert!(first < second && second < third);
+    }
+
+    // A single worker: extracting the PDF on it would hold back every other read.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn pdf_extraction_does_not_block_other_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf_path = dir.path().join("book.pdf");
+        let pages: Vec<_> = (1..=300).map(|page| format!("Page {page}")).collect();
+        let pages: Vec<_> = pages.iter().map(String::as_str).collect();
+        std::fs::write(&pdf_path, pdf_with_pages(&pages)).unwrap();
+
+        let finished = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let read = |path: PathBuf| {
+            let finished = finished.clone();
+            tokio::spawn(async move {
+                let file = FileReader::default().read(path.clone()).await;
+                finished.lock().unwrap().push(path);
+                file.unwrap()
+            })
+        };
+        let pdf = read(pdf_path.clone());
+        // Lets the worker start the extraction before the other reads are queued.
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        let mut sources = vec![];
+        for index in 0..4 {
+            let path = dir.path().join(format!("source_{index}.rs"));
+            std::fs::write(&path, format!("fn source_{index}() {{}}")).unwrap();
+            sources.push(read(path));
+        }
+        for source in sources {
+            source.await.unwrap();
+        }
+        let pdf = pdf.await.unwrap();
+
+        assert!(pdf.content.contains("Page 300"));
+        assert_eq!(finished.lock().unwrap().last(), Some(&pdf_path));
+    }
+
+    #[tokio::test]
+    async fn reader_dispatches_uppercase_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Page.HTML");
+        std::fs::write(&path, "<html><body><p>Hello</p></body></html>").unwrap();
+
+        let file = FileReader::default().read(path).await.unwrap();
+        assert_eq!(file.extension, "html");
+        assert!(!file.content.contains("<p>"));
+        assert!(file.content.contains("Hello"));
+    }
+
+    #[test]
+    fn line_numbers_skip_rewritten_files() {
+        let content = "// comment\nfn main() {}\n";
+        let mut prompt_builder = PromptBuilder::new(String::new()).line_numbers(true);
+        prompt_builder
+            .add_content("a.rs", "rs", content.to_string())
+            .unwrap();
+        assert!(
+            prompt_builder.files()[0]
+                .1
+                .contains("1| // comment\n2| fn main() {}")
+        );
+
+        let mut prompt_builder = PromptBuilder::new(String::new())
+            .line_numbers(true)
+            .strip_comments(true);
+        prompt_builder
+            .add_content("a.rs", "rs", content.to_string())
+            .unwrap();
+        assert!(!prompt_builder.files()[0].1.contains("| "));
+    }
+
+    #[test]
+    fn keyword_window_picks_the_matching_multi_byte_window() {
+        let content = format!(
+            "{}\n漢字 is used here\n{}",
+            "ä".repeat(200),
+            "ö".repeat(200)
+        );
+        let mut prompt_builder = truncating_builder(40, ChunkStrategy::KeywordWindow);
+        let added = prompt_builder.add_content("x.txt", "txt", content).unwrap();
+
+        assert!(added.truncated);
+        let (_, content) = &prompt_builder.files()[0];
+        assert!(content.contains("漢字 is used here"));
+        assert!(content.contains("...[skipped "));
+    }
+}
@@ -0,0 +1,33 @@
+use tiktoken_rs::{CoreBPE, cl100k_base_singleton};
+
+pub trait TokenCounter: Send + Sync {
+    fn count(&self, content: &str) -> u64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeuristicCounter;
+
+impl TokenCounter for HeuristicCounter {
+    fn count(&self, content: &str) -> u64 {
+        content.len() as u64 / 4
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct TiktokenCounter {
+    bpe: &'static CoreBPE,
+}
+
+impl Default for TiktokenCounter {
+    fn default() -> Self {
+        Self {
+            bpe: cl100k_base_singleton(),
+        }
+    }
+}
+
+impl TokenCounter for TiktokenCounter {
+    fn count(&self, content: &str) -> u64 {
+        self.bpe.encode_ordinary(content).len() as u64
+    }
+}